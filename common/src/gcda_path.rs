@@ -0,0 +1,87 @@
+//! Works out where a `.gcda` file ends up, following the rules GCC and libgcov apply to the path
+//! of the object it belongs to.
+//!
+//! At compile time, `-fprofile-dir=<dir>` moves the file into `<dir>`, naming it after the object's
+//! full path [`mangle`]d into a single component (absolute object paths are instead appended to
+//! `<dir>` as-is). At run time, libgcov strips `GCOV_PREFIX_STRIP` leading directories from that
+//! path and prepends `GCOV_PREFIX`.
+
+/// Environment variable libgcov reads the directory to relocate `.gcda` files into from
+pub const GCOV_PREFIX_ENV: &str = "GCOV_PREFIX";
+
+/// Environment variable libgcov reads the number of leading directories to strip from
+pub const GCOV_PREFIX_STRIP_ENV: &str = "GCOV_PREFIX_STRIP";
+
+/// Flattens `path` into a single path component as GCC's `mangle_path()` does: each `/` becomes
+/// `#` and each `..` component becomes `^`.
+pub fn mangle(path: &str) -> String {
+    path.split('/')
+        .map(|component| if component == ".." { "^" } else { component })
+        .collect::<Vec<_>>()
+        .join("#")
+}
+
+/// The paths GCC may name the `.gcda` file of an object at compile time, where `object_base` is the
+/// object's absolute path less its extension. Under a profile directory the name depends on how the
+/// object's path was passed to GCC: relative ones are [`mangle`]d onto the compiler's cwd, while
+/// absolute ones are appended unchanged, so both are returned (the mangled one first).
+pub fn compile_time_paths(object_base: &str, profile_dir: Option<&str>) -> Vec<String> {
+    match profile_dir {
+        Some(dir) => vec![
+            format!("{}/{}.gcda", dir, mangle(object_base)),
+            format!("{}/{}.gcda", dir, object_base),
+        ],
+        None => vec![format!("{}.gcda", object_base)],
+    }
+}
+
+/// The relocation libgcov applies to every `.gcda` path when a process writes its files.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Relocation {
+    pub prefix: Option<String>,
+    pub strip: usize,
+}
+
+impl Relocation {
+    /// Reads the relocation a process started in the current environment would apply.
+    pub fn from_env() -> Self {
+        let prefix = std::env::var(GCOV_PREFIX_ENV).ok();
+        // libgcov parses the level with `atoi()`, so anything unparsable (or negative) means 0
+        let strip = std::env::var(GCOV_PREFIX_STRIP_ENV).ok()
+            .and_then(|strip| strip.trim().parse::<i64>().ok())
+            .map_or(0, |strip| strip.max(0) as usize);
+
+        Self::new(prefix, strip)
+    }
+
+    pub fn new(prefix: Option<String>, strip: usize) -> Self {
+        // A trailing `/` is dropped, and stripping without a prefix strips relative to the cwd
+        let prefix = match prefix.as_deref().map(|prefix| prefix.strip_suffix('/').unwrap_or(prefix)) {
+            Some("") | None if strip > 0 => Some(".".to_string()),
+            Some("") | None => None,
+            Some(prefix) => Some(prefix.to_string()),
+        };
+
+        Self { prefix, strip }
+    }
+
+    /// Returns the path libgcov writes the `.gcda` file compiled as `path` to.
+    pub fn apply(&self, path: &str) -> String {
+        let mut stripped = path;
+        if self.strip > 0 {
+            // A leading separator isn't counted; once `strip` separators have been passed, the
+            // path continues from the last of them (which is kept)
+            let skip = usize::from(path.starts_with('/'));
+            let separators = path.char_indices().skip(skip).filter(|(_, c)| *c == '/');
+            if let Some((idx, _)) = separators.take(self.strip).last() {
+                stripped = &path[idx..];
+            }
+        }
+
+        match &self.prefix {
+            Some(prefix) if stripped.starts_with('/') => format!("{}{}", prefix, stripped),
+            Some(prefix) => format!("{}/{}", prefix, stripped),
+            None => stripped.to_string(),
+        }
+    }
+}
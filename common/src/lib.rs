@@ -3,8 +3,12 @@ use std::cmp;
 
 use serde::{Deserialize, Serialize};
 
+pub mod gcda_path;
 pub mod reader;
 pub mod prelude;
+pub mod protocol;
+pub mod shm;
+pub mod writer;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ProgCoverage {
@@ -0,0 +1,269 @@
+//! The IPC protocol spoken between `quikcov-preload` and the `quikcov` runner.
+//!
+//! Every frame on the wire is a big-endian `u32` length followed by that many bytes of a
+//! postcard-encoded [`Message`]. Each process that loads the preload opens its part of the stream
+//! with a [`Body::Hello`] carrying the protocol [`VERSION`] it was built against; the runner
+//! rejects any process whose version differs from its own. In the other direction, the runner
+//! exports its version to the target in [`VERSION_ENV`] so that a mismatched preload can refuse to
+//! capture rather than send messages the runner can't interpret.
+//!
+//! A `.gcda` file is sent either whole ([`Body::Gcda`]) or, when it outgrows [`GCDA_CHUNK_LEN`]
+//! while being written, streamed as a [`Body::GcdaBegin`], a run of [`Body::GcdaChunk`]s and a
+//! [`Body::GcdaEnd`], so that the preload never holds more than a chunk of it at a time.
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// The version of the protocol implemented by this crate. Bump this on any change to [`Message`].
+pub const VERSION: u32 = 6;
+
+/// Environment variable holding the file descriptor of the pipe the preload writes to
+pub const PIPE_FD_ENV: &str = "QUIKCOV_LDPRELOAD_PIPE_FD";
+
+/// Environment variable holding the protocol version the runner expects
+pub const VERSION_ENV: &str = "QUIKCOV_PROTOCOL_VERSION";
+
+/// Environment variable enabling the preload's forkserver, set to the point at which it starts
+pub const FORKSERVER_ENV: &str = "QUIKCOV_FORKSERVER";
+
+/// [`FORKSERVER_ENV`] value starting the forkserver the first time the target reads from stdin
+pub const FORKSERVER_STDIN_READ: &str = "stdin-read";
+
+/// [`FORKSERVER_ENV`] value starting the forkserver just before the target's `main()`
+pub const FORKSERVER_MAIN: &str = "main";
+
+/// Environment variable holding the file descriptor the forkserver reads requests from.
+///
+/// Each 4-byte write to it asks the forkserver for one child; closing it stops the forkserver.
+pub const FORKSERVER_FD_ENV: &str = "QUIKCOV_FORKSERVER_FD";
+
+/// Environment variable set (to any value) when each forkserver child should lead a process group
+/// of its own, for the runner to stop it along with its descendants when it times out
+pub const FORKSERVER_GROUPS_ENV: &str = "QUIKCOV_FORKSERVER_GROUPS";
+
+/// Environment variable holding the file descriptor the preload reads persistent-mode inputs from
+pub const PERSISTENT_FD_ENV: &str = "QUIKCOV_PERSISTENT_FD";
+
+/// Environment variable selecting how the preload captures coverage
+pub const CAPTURE_ENV: &str = "QUIKCOV_CAPTURE";
+
+/// [`CAPTURE_ENV`] value intercepting the `.gcda` files libgcov writes (the default)
+pub const CAPTURE_FILE_IO: &str = "file-io";
+
+/// [`CAPTURE_ENV`] value serializing the counters registered with libgcov directly, so that no
+/// `.gcda` file is ever written
+pub const CAPTURE_GCOV_INFO: &str = "gcov-info";
+
+/// Environment variable holding the [`Fallback`] the preload applies when it can't reach the runner
+pub const FALLBACK_ENV: &str = "QUIKCOV_FALLBACK";
+
+/// Environment variable holding the number of a signal that makes the target exit (dumping its
+/// coverage) on receipt, for targets such as servers that never exit by themselves
+pub const DUMP_SIGNAL_ENV: &str = "QUIKCOV_DUMP_SIGNAL";
+
+/// Environment variable holding the port (0 for any) whose sockets the preload replaces with a
+/// fake connection carrying the seed read from stdin
+pub const DESOCK_ENV: &str = "QUIKCOV_DESOCK";
+
+/// Environment variable set when the stdin of a desocketed target holds several messages, each
+/// preceded by its length as a big-endian u32, to be delivered one at a time
+pub const DESOCK_FRAMED_ENV: &str = "QUIKCOV_DESOCK_FRAMED";
+
+/// The size of the chunks `.gcda` files are streamed in
+pub const GCDA_CHUNK_LEN: usize = 64 * 1024;
+
+/// Frames longer than this are rejected rather than allocated
+pub const MAX_FRAME_LEN: usize = 1 << 30;
+
+/// A single message sent from an instrumented process to the runner.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Message {
+    /// The process that produced the message
+    pub pid: u32,
+    /// The parent of the process that produced the message
+    pub ppid: u32,
+    pub body: Body,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Body {
+    /// The first message sent by every process
+    Hello { version: u32 },
+    /// The full contents of a `.gcda` file written by the process
+    Gcda(Gcda),
+    /// A `.gcda` file whose contents were placed in the shared-memory region (see [`crate::shm`])
+    GcdaShm { filepath: String, offset: u64, len: u64 },
+    /// Starts a `.gcda` file streamed in chunks, identified by `file` within the process
+    GcdaBegin { file: u32, filepath: String },
+    /// The `seq`-th chunk (counting from 0) of a streamed `.gcda` file
+    GcdaChunk { file: u32, seq: u32, data: Vec<u8> },
+    /// A chunk of a streamed `.gcda` file that was placed in the shared-memory region
+    GcdaChunkShm { file: u32, seq: u32, offset: u64, len: u64 },
+    /// Completes a streamed `.gcda` file after the given number of chunks
+    GcdaEnd { file: u32, chunks: u32 },
+    /// The preload has finished dumping the coverage of the process, or (in persistent mode) the
+    /// coverage of a single seed
+    DumpComplete { seed: Option<u64> },
+    /// The preload encountered an error that the runner should know about
+    Error(String),
+    /// The process called `exit()` with the given status
+    ExitStatus(i32),
+    /// The forkserver forked off a child to run the next seed
+    ForkserverSpawned { child: u32 },
+    /// A child of the forkserver terminated with the given `waitpid()` status, having used at most
+    /// `max_rss` KiB of memory
+    ForkserverExited { child: u32, wait_status: i32, max_rss: u64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Gcda {
+    pub filepath: String,
+    pub data: Vec<u8>,
+}
+
+/// What the preload does with a `.gcda` file it can't send to the runner.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Fallback {
+    /// Write the file where libgcov meant to, as if the preload weren't there
+    #[default]
+    Passthrough,
+    /// Write the file into the given directory, named after the process and its original path
+    Spool(PathBuf),
+    /// Discard the file, only counting how many were lost
+    Drop,
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "passthrough" => Ok(Fallback::Passthrough),
+            "drop" => Ok(Fallback::Drop),
+            _ => match s.strip_prefix("spool:") {
+                Some(dir) if !dir.is_empty() => Ok(Fallback::Spool(PathBuf::from(dir))),
+                _ => Err(format!("invalid fallback `{}` (expected `passthrough`, `drop` or `spool:<dir>`)", s)),
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Fallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fallback::Passthrough => write!(f, "passthrough"),
+            Fallback::Spool(dir) => write!(f, "spool:{}", dir.display()),
+            Fallback::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The stream ended partway through a frame
+    Truncated,
+    /// A frame length exceeded [`MAX_FRAME_LEN`]
+    FrameTooLarge(usize),
+    Encode(postcard::Error),
+    Decode(postcard::Error),
+    VersionMismatch { expected: u32, found: u32 },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Truncated => write!(f, "stream ended partway through a frame"),
+            Error::FrameTooLarge(len) => write!(f, "frame length {} exceeds maximum of {}", len, MAX_FRAME_LEN),
+            Error::Encode(e) => write!(f, "failed to encode message: {}", e),
+            Error::Decode(e) => write!(f, "failed to decode message: {}", e),
+            Error::VersionMismatch { expected, found } => write!(f, "protocol version mismatch (expected {}, found {})", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Checks a version received in a [`Body::Hello`] (or through [`VERSION_ENV`]) against our own.
+pub fn check_version(found: u32) -> Result<(), Error> {
+    if found == VERSION {
+        Ok(())
+    } else {
+        Err(Error::VersionMismatch { expected: VERSION, found })
+    }
+}
+
+/// Encodes `message` as a complete frame, length prefix included.
+pub fn encode(message: &Message) -> Result<Vec<u8>, Error> {
+    let mut frame = vec![0u8; 4];
+    frame = postcard::to_extend(message, frame).map_err(Error::Encode)?;
+    let len = frame.len() - 4;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge(len))
+    }
+    frame[..4].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(frame)
+}
+
+/// Encodes `message` and writes the frame to `writer`.
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), Error> {
+    writer.write_all(&encode(message)?)?;
+    Ok(())
+}
+
+/// Reads framed messages from a byte stream.
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Reads the next message, or returns `Ok(None)` if the stream ended cleanly between frames.
+    pub fn read_message(&mut self) -> Result<Option<Message>, Error> {
+        let mut length_arr = [0u8; 4];
+        let mut filled = 0;
+        while filled < length_arr.len() {
+            match self.inner.read(&mut length_arr[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(Error::Truncated),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+
+        let length = u32::from_be_bytes(length_arr) as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(Error::FrameTooLarge(length))
+        }
+
+        self.buf.resize(length, 0);
+        self.inner.read_exact(&mut self.buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated,
+            _ => Error::Io(e),
+        })?;
+
+        postcard::from_bytes(&self.buf).map(Some).map_err(Error::Decode)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
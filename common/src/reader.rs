@@ -6,19 +6,25 @@ use fxhash::FxBuildHasher;
 use crate::{FileCoverage, FnCoverage, LineCoverage, BlockCoverage, ProgCoverage};
 
 const GCOV_ARC_ON_TREE: u32 = 1 << 0;
+#[allow(dead_code)]
 const GCOV_ARC_FAKE: u32 = 1 << 1;
 //const GCOV_ARC_FALLTHROUGH: u32 = 1 << 2;
-const GCOV_TAG_FUNCTION: u32 = 0x0100_0000;
+pub(crate) const GCOV_TAG_FUNCTION: u32 = 0x0100_0000;
 const GCOV_TAG_BLOCKS: u32 = 0x0141_0000;
 const GCOV_TAG_ARCS: u32 = 0x0143_0000;
+#[allow(dead_code)]
 const GCOV_TAG_CONDS: u32 = 0x0147_0000;
+#[allow(dead_code)]
 const GCOV_TAG_PATHS: u32 = 0x0149_0000;
 const GCOV_TAG_LINES: u32 = 0x0145_0000;
-const GCOV_TAG_COUNTER_ARCS: u32 = 0x01a1_0000;
-const GCOV_TAG_OBJECT_SUMMARY: u32 = 0xa100_0000;
+pub(crate) const GCOV_TAG_COUNTER_ARCS: u32 = 0x01a1_0000;
+pub(crate) const GCOV_TAG_OBJECT_SUMMARY: u32 = 0xa100_0000;
 const GCOV_TAG_PROGRAM_SUMMARY: u32 = 0xa300_0000;
+#[allow(dead_code)]
 const GCOV_TAG_AFDO_FILE_NAMES: u32 = 0xaa00_0000;
+#[allow(dead_code)]
 const GCOV_TAG_AFDO_FUNCTION: u32 = 0xac00_0000;
+#[allow(dead_code)]
 const GCOV_TAG_AFDO_WORKING_SET: u32 = 0xaf00_0000;
 
 // We don't currently support GCC < 8
@@ -33,6 +39,7 @@ pub enum Error {
     Checksum,
     Endianness,
     Length,
+    Stamp,
     Utf8,
     IncompleteFile,
     InsufficientBytes,
@@ -45,6 +52,9 @@ pub enum Error {
 #[derive(Clone)]
 pub struct Gcno {
     pub version: u32,
+    /// Identifies the compilation that produced the file; the `.gcda` files of the same object
+    /// carry the same stamp
+    pub stamp: Option<u32>,
     pub chksum: u32,
     pub cwd: Option<String>,
     pub ident_fn_idx: HashMap<u32, usize, FxBuildHasher>,
@@ -120,9 +130,9 @@ impl Gcno {
 
 
         // This gets added in commit 72e0c742bd01f8e7e6dcca64042b9ad7e75979de, which was subsequently released in GCC 11.3
-        let _bbg_stamp = if version >= 113 { Some(reader.get_u32()?) } else { None };
+        let stamp = if version >= 113 { Some(reader.get_u32()?) } else { None };
         let chksum = reader.get_u32()?;
-        
+
         let cwd = if version >= 90 { Some(reader.get_string(version)?) } else { None };
         if let Some(cwd) = &cwd {
             log::debug!("cwd={}", cwd);
@@ -177,8 +187,8 @@ impl Gcno {
                 elem_tag => {
                     log::warn!("unrecognized element tag {} found in gcno file", elem_tag);
                     let mut length = reader.get_u32()? as usize;
-                    if version < 130 {
-                        length = length * 4;
+                    if version < 120 {
+                        length *= 4;
                     }
                     log::debug!("unrecognized element tag {} had length {}", elem_tag, length);
                     reader.discard(length)?;
@@ -188,6 +198,7 @@ impl Gcno {
 
         Ok(Self {
             version,
+            stamp,
             chksum,
             cwd,
             ident_fn_idx,
@@ -195,10 +206,58 @@ impl Gcno {
         })
     }
 
+    /// Checks whether the `.gcda` file `input` could have been produced by the object this file
+    /// describes: its stamp must match ours, and every function it records must be one of ours
+    /// with the same checksums.
+    pub fn matches_gcda(&self, input: &[u8]) -> Result<bool, Error> {
+        let mut reader = ByteReader::new(input);
+
+        let Magic::Gcda = reader.get_magic_number()? else {
+            return Err(Error::Value("file type gcda needed but gcno found"))
+        };
+        let version = reader.get_version()?;
+        let stamp = if version >= 113 { Some(reader.get_u32()?) } else { None };
+        reader.get_u32()?;
+
+        if version != self.version || stamp != self.stamp {
+            return Ok(false)
+        }
+
+        while !reader.is_empty() {
+            let tag = reader.get_u32()?;
+            if tag == 0 {
+                break
+            }
+
+            let length = reader.get_u32()?;
+            // Counters that are all zero are recorded with a negative length and no data
+            let mut length = if length >= 0x80_00_00_00 { 0 } else { length as usize };
+            if version < 120 {
+                length *= 4;
+            }
+            let mut record = ByteReader::new(reader.get_bytes(length)?);
+
+            if tag == GCOV_TAG_FUNCTION && length != 0 {
+                let ident = record.get_u32()?;
+                let line_chksum = record.get_u32()?;
+                let cfg_chksum = if version >= 47 { Some(record.get_u32()?) } else { None };
+
+                let Some(function) = self.ident_fn_idx.get(&ident).and_then(|&idx| self.functions.get(idx)) else {
+                    return Ok(false)
+                };
+                if line_chksum != function.line_chksum || cfg_chksum != function.cfg_chksum {
+                    return Ok(false)
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
     fn read_function(reader: &mut ByteReader<'_>, version: u32) -> Result<GcnoFunction, Error> {
         let mut length = reader.get_u32()? as usize;
-        if version < 130 {
-            length = length * 4;
+        if version < 120 {
+            length *= 4;
         }
 
         let Some(remainder) = reader.remainder().get(..length) else {
@@ -252,7 +311,7 @@ impl Gcno {
         let length = reader.get_u32()? as usize;
 
         // TODO: didn't used to have / 4--version change?
-        let count = (((length / 4).checked_sub(1).ok_or(Error::InsufficientBytes)?) / 2) as usize;
+        let count = ((length / 4).checked_sub(1).ok_or(Error::InsufficientBytes)?) / 2;
         let block_id = reader.get_u32()? as usize;
 
         let Some(block) = function.blocks.get_mut(block_id) else {
@@ -341,6 +400,10 @@ impl FileCovBuilder {
         }
     }
 
+    pub fn gcno(&self) -> &Gcno {
+        &self.gcno
+    }
+
     pub fn build(mut self) -> Result<ProgCoverage, Error> {
         self.account_on_tree_arcs()?;
         self.account_lines()?;
@@ -449,7 +512,7 @@ impl FileCovBuilder {
 
             let mut visited = HashSet::default();
             for block_id in 0..function.blocks.len() {
-                Self::propagate_counts(&mut function.blocks, &mut function.edges, block_id, None, &mut visited);
+                Self::propagate_counts(&function.blocks, &mut function.edges, block_id, None, &mut visited);
             }
 
             for edge in function.edges.iter().rev() {
@@ -483,7 +546,7 @@ impl FileCovBuilder {
         let mut negative_excess = 0;
         let block = &blocks[block_no];
         for edge_id in block.src.iter() {
-            if pred_arc.map_or(true, |x| *edge_id != x) {
+            if pred_arc.is_none_or(|x| *edge_id != x) {
                 let edge = &edges[*edge_id];
                 positive_excess += if (edge.flags & GCOV_ARC_ON_TREE) != 0 {
                     let source = edge.src;
@@ -494,7 +557,7 @@ impl FileCovBuilder {
             }
         }
         for edge_id in block.dst.iter() {
-            if pred_arc.map_or(true, |x| *edge_id != x) {
+            if pred_arc.is_none_or(|x| *edge_id != x) {
                 let edge = &edges[*edge_id];
                 negative_excess += if (edge.flags & GCOV_ARC_ON_TREE) != 0 {
                     let destination = edge.dst;
@@ -504,11 +567,7 @@ impl FileCovBuilder {
                 };
             }
         }
-        let excess = if positive_excess >= negative_excess {
-            positive_excess - negative_excess
-        } else {
-            negative_excess - positive_excess
-        };
+        let excess = positive_excess.abs_diff(negative_excess);
         if let Some(id) = pred_arc {
             let edge = &mut edges[id];
             edge.counter = excess;
@@ -524,22 +583,17 @@ impl FileCovBuilder {
         };
         let version = reader.get_version()?;
 
-        if version >= 113 {
-            let _bbg_stamp = reader.get_u32()?;
-        }
-
-        let chksum = reader.get_u32()?;
+        let stamp = if version >= 113 { Some(reader.get_u32()?) } else { None };
+        // The checksum of the object, which .gcno files don't record (GCC writes 0 in its place)
+        reader.get_u32()?;
 
         if version != self.gcno.version {
             return Err(Error::VersionMismatch)
         }
 
-        // Checksums are currently not working in GCC 13.3
-        /*
-        if chksum != self.gcno.chksum {
-            return Err(Error::Checksum)
+        if stamp != self.gcno.stamp {
+            return Err(Error::Stamp)
         }
-        */
 
         while !reader.is_empty() {
             let tag = reader.get_u32()?;
@@ -549,8 +603,8 @@ impl FileCovBuilder {
                 GCOV_TAG_OBJECT_SUMMARY => {
                     log::trace!("parsing gcda Object Summary element");
                     let mut length = reader.get_u32()? as usize;
-                    if version < 130 {
-                        length = length * 4;
+                    if version < 120 {
+                        length *= 4;
                     }
 
                     if length == 0 {
@@ -573,8 +627,8 @@ impl FileCovBuilder {
                 GCOV_TAG_PROGRAM_SUMMARY => {
                     log::trace!("parsing gcda program summary element");
                     let mut length = reader.get_u32()? as usize;
-                    if version < 130 {
-                        length = length * 4;
+                    if version < 120 {
+                        length *= 4;
                     }
 
                     if length == 0 {
@@ -604,8 +658,8 @@ impl FileCovBuilder {
                         length = 0;
                     }
 
-                    if version < 130 {
-                        length = length * 4;
+                    if version < 120 {
+                        length *= 4;
                     }
                     log::warn!("unrecognized element tag {}  of length {} found in gcda file", elem_tag, length);
                     reader.discard(length)?;
@@ -624,7 +678,7 @@ impl FileCovBuilder {
             return Ok(())
         }
 
-        let expected_length = if version >= 130 {
+        let expected_length = if version >= 120 {
             3 * 4
         } else if version >= 47 {
             3
@@ -648,12 +702,9 @@ impl FileCovBuilder {
             return Err(Error::Value("internal: invalid function index for function identifier while parsing functions"))
         };
 
-        // Checksums currently not working in GCC 13.3
-        /*
         if line_chksum != function.line_chksum || cfg_chksum != function.cfg_chksum {
             return Err(Error::Checksum)
         }
-        */
 
         self.current_fn_idx = Some(*function_idx);
 
@@ -679,7 +730,7 @@ impl FileCovBuilder {
             return Err(Error::Value("internal: invalid function index for function identifier while parsing arcs"))
         };
 
-        let edge_count = if version >= 130 {
+        let edge_count = if version >= 120 {
             (length / 4) / 2
         } else {
             length / 2
@@ -706,6 +757,45 @@ impl FileCovBuilder {
 }
 
 
+/// Reads the stamp from the header of a `.gcda` file, if its version records one.
+pub fn gcda_stamp(input: &[u8]) -> Result<Option<u32>, Error> {
+    let mut reader = ByteReader::new(input);
+
+    let Magic::Gcda = reader.get_magic_number()? else {
+        return Err(Error::Value("file type gcda needed but gcno found"))
+    };
+    let version = reader.get_version()?;
+
+    Ok(if version >= 113 { Some(reader.get_u32()?) } else { None })
+}
+
+/// Decodes a raw gcov version word (as found in `.gcno`/`.gcda` headers and `gcov_info`) into the
+/// numeric form used throughout this module, e.g. `122` for GCC 12.2.
+pub fn decode_version(raw: u32) -> Result<u32, Error> {
+    // FIXME: assumes little endianness
+    let [b0, b1, b2, b3] = raw.to_ne_bytes();
+
+    if b0 != b'*' {
+        return Err(Error::Version)
+    }
+
+
+    if let Some(n3) =  b3.checked_sub(b'A') {
+        let (Some(n2), Some(n1)) = (b2.checked_sub(b'0'), b1.checked_sub(b'0')) else {
+            return Err(Error::Version)
+        };
+
+        Ok(100 * u32::from(n3) + 10 * u32::from(n2) + u32::from(n1))
+    } else {
+        let (Some(n1), Some(n3)) = (b1.checked_sub(b'0'), b3.checked_sub(b'0')) else {
+            return Err(Error::Version)
+        };
+
+        Ok(10 * u32::from(n3) + u32::from(n1))
+    }
+}
+
+
 struct ByteReader<'a> {
     slice: &'a [u8],
 }
@@ -761,11 +851,11 @@ impl<'a> ByteReader<'a> {
 
     #[inline]
     pub fn get_string(&mut self, version: u32) -> Result<String, Error> {
-        // This changed in commit 23eb66d1d46a34cb28c4acbdf8a1deb80a7c5a05, which was included in version 13.0
+        // This changed in commit 23eb66d1d46a34cb28c4acbdf8a1deb80a7c5a05, which was included in version 12.1
 
         let mut length = self.get_u32()? as usize;
-        if version < 130 {
-            length = length * 4;
+        if version < 120 {
+            length *= 4;
         }
 
         if length == 0 {
@@ -790,27 +880,7 @@ impl<'a> ByteReader<'a> {
 
     #[inline]
     fn get_version(&mut self) -> Result<u32, Error> {
-        // FIXME: assumes little endianness
-        let [b0, b1, b2, b3] = self.get_array::<4>()?;
-
-        if b0 != b'*' {
-            return Err(Error::Version)
-        }
-
-
-        if let Some(n3) =  b3.checked_sub(b'A') {
-            let (Some(n2), Some(n1)) = (b2.checked_sub(b'0'), b1.checked_sub(b'0')) else {
-                return Err(Error::Version)
-            };
-
-            Ok(100 * u32::from(n3) + 10 * u32::from(n2) + u32::from(n1))
-        } else {
-            let (Some(n1), Some(n3)) = (b1.checked_sub(b'0'), b3.checked_sub(b'0')) else {
-                return Err(Error::Version)
-            };
-
-            Ok(10 * u32::from(n3) + u32::from(n1))
-        }
+        decode_version(self.get_u32()?)
     }
 
     #[inline]
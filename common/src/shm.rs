@@ -0,0 +1,122 @@
+//! A shared-memory region that the preload copies `.gcda` payloads into instead of the IPC pipe.
+//!
+//! The runner creates the backing memory (typically a memfd), passes its file descriptor to the
+//! target in [`SHM_FD_ENV`], and rewinds the region between seeds. Any process sharing the mapping
+//! reserves space for a payload with a single atomic add, copies the payload in, and then sends a
+//! small [`Body::GcdaShm`](crate::protocol::Body::GcdaShm) message over the pipe naming its offset
+//! and length. When the region runs out of space the preload falls back to sending the payload
+//! over the pipe itself.
+//!
+//! Space is only ever handed out from the front, never given back before the region is rewound.
+//! A ring would have to free payloads as the runner reads them, but the processes of a seed
+//! reserve space concurrently and announce their payloads in whatever order their messages reach
+//! the pipe, so space isn't freed in the order it was reserved.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Environment variable holding the file descriptor of the shared-memory region
+pub const SHM_FD_ENV: &str = "QUIKCOV_SHM_FD";
+
+const SHM_MAGIC: u64 = u64::from_be_bytes(*b"quikcshm");
+
+/// Bytes reserved at the start of the region for the header
+pub const HEADER_LEN: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    capacity: u64,
+    head: AtomicU64,
+}
+
+/// A view of a mapped shared-memory region.
+pub struct ShmRegion {
+    base: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for ShmRegion {}
+unsafe impl Sync for ShmRegion {}
+
+impl ShmRegion {
+    /// Formats a freshly-mapped region of `len` bytes.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a writable, 8-byte aligned mapping of at least `len` bytes that
+    /// outlives the returned value.
+    pub unsafe fn init(base: *mut u8, len: usize) -> Option<Self> {
+        if len <= HEADER_LEN {
+            return None
+        }
+
+        let header = base as *mut Header;
+        std::ptr::addr_of_mut!((*header).magic).write(SHM_MAGIC);
+        std::ptr::addr_of_mut!((*header).capacity).write((len - HEADER_LEN) as u64);
+        std::ptr::addr_of_mut!((*header).head).write(AtomicU64::new(0));
+
+        Some(Self { base, len })
+    }
+
+    /// Attaches to a region previously formatted with [`ShmRegion::init`].
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a writable, 8-byte aligned mapping of at least `len` bytes that
+    /// outlives the returned value.
+    pub unsafe fn attach(base: *mut u8, len: usize) -> Option<Self> {
+        if len <= HEADER_LEN {
+            return None
+        }
+
+        let header = &*(base as *const Header);
+        if header.magic != SHM_MAGIC || header.capacity != (len - HEADER_LEN) as u64 {
+            return None
+        }
+
+        Some(Self { base, len })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.base as *const Header) }
+    }
+
+    /// The number of payload bytes the region can hold.
+    pub fn capacity(&self) -> usize {
+        self.len - HEADER_LEN
+    }
+
+    /// Discards every payload in the region. Must only be called while no process is writing to it.
+    pub fn reset(&self) {
+        self.header().head.store(0, Ordering::SeqCst);
+    }
+
+    /// Copies `data` into the region, returning the offset it was written at, or `None` if the
+    /// region doesn't have enough space left.
+    pub fn write(&self, data: &[u8]) -> Option<u64> {
+        let len = data.len() as u64;
+        let capacity = self.capacity() as u64;
+
+        let mut head = self.header().head.load(Ordering::Relaxed);
+        let offset = loop {
+            let end = head.checked_add(len).filter(|&end| end <= capacity)?;
+            match self.header().head.compare_exchange_weak(head, end, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(offset) => break offset,
+                Err(current) => head = current,
+            }
+        };
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.base.add(HEADER_LEN + offset as usize), data.len());
+        }
+
+        Some(offset)
+    }
+
+    /// Returns the `len` bytes stored at `offset`, or `None` if they fall outside the region.
+    pub fn read(&self, offset: u64, len: u64) -> Option<&[u8]> {
+        let end = offset.checked_add(len).filter(|&end| end <= self.capacity() as u64)?;
+        let start = HEADER_LEN + offset as usize;
+        Some(unsafe { std::slice::from_raw_parts(self.base.add(start), (end - offset) as usize) })
+    }
+}
@@ -0,0 +1,78 @@
+//! Serializes `.gcda` files in the same layout libgcov writes them, for when coverage is captured
+//! straight from a process's in-memory counters rather than from the files libgcov produces.
+
+use crate::reader::{self, Error, GCOV_TAG_COUNTER_ARCS, GCOV_TAG_FUNCTION, GCOV_TAG_OBJECT_SUMMARY};
+
+pub struct GcdaWriter {
+    version: u32,
+    buf: Vec<u8>,
+}
+
+impl GcdaWriter {
+    /// Starts a `.gcda` file; `raw_version`, `stamp` and `checksum` are taken as-is from the
+    /// object's `gcov_info`.
+    pub fn new(raw_version: u32, stamp: u32, checksum: u32) -> Result<Self, Error> {
+        let version = reader::decode_version(raw_version)?;
+
+        let mut writer = Self {
+            version,
+            buf: Vec::new(),
+        };
+
+        writer.put_u32(u32::from_be_bytes(*b"gcda"));
+        writer.put_u32(raw_version);
+        writer.put_u32(stamp);
+        if version >= 113 {
+            writer.put_u32(checksum);
+        }
+
+        Ok(writer)
+    }
+
+    /// Writes the object summary for a single run whose largest arc counter was `sum_max`.
+    pub fn object_summary(&mut self, runs: u32, sum_max: u32) {
+        self.put_u32(GCOV_TAG_OBJECT_SUMMARY);
+        self.put_length(2);
+        self.put_u32(runs);
+        self.put_u32(sum_max);
+    }
+
+    /// Opens the record of a function; its counters follow through [`GcdaWriter::counters`].
+    pub fn function(&mut self, ident: u32, lineno_checksum: u32, cfg_checksum: u32) {
+        self.put_u32(GCOV_TAG_FUNCTION);
+        self.put_length(3);
+        self.put_u32(ident);
+        self.put_u32(lineno_checksum);
+        self.put_u32(cfg_checksum);
+    }
+
+    /// Writes the placeholder libgcov emits for a function whose counters live in another object
+    /// (e.g. a COMDAT function deduplicated by the linker).
+    pub fn empty_function(&mut self) {
+        self.put_u32(GCOV_TAG_FUNCTION);
+        self.put_u32(0);
+    }
+
+    /// Writes the values of counter kind `kind` (0 being arcs) for the current function.
+    pub fn counters(&mut self, kind: u32, values: &[u64]) {
+        self.put_u32(GCOV_TAG_COUNTER_ARCS + (kind << 17));
+        self.put_length(2 * values.len() as u32);
+        for &value in values {
+            self.put_u32(value as u32);
+            self.put_u32((value >> 32) as u32);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Record lengths are counted in 4-byte words before GCC 12, and in bytes from then on.
+    fn put_length(&mut self, words: u32) {
+        self.put_u32(if self.version >= 120 { words * 4 } else { words });
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_ne_bytes());
+    }
+}
@@ -0,0 +1,32 @@
+#include <stdio.h>
+#include <string.h>
+
+static int classify(const char *s)
+{
+    int score = 0;
+    for (size_t i = 0; i < strlen(s); i++) {
+        if (s[i] == 'a' || s[i] == 'e')
+            score += 2;
+        else if (s[i] >= '0' && s[i] <= '9')
+            score -= 1;
+        else
+            score++;
+    }
+    return score;
+}
+
+static int never_called(int x)
+{
+    return x * 2;
+}
+
+int main(int argc, char **argv)
+{
+    int total = 0;
+    for (int i = 1; i < argc; i++)
+        total += classify(argv[i]);
+    if (total < 0)
+        total = never_called(total);
+    printf("%d\n", total);
+    return 0;
+}
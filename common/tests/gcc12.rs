@@ -0,0 +1,100 @@
+use quikcov_common::prelude::*;
+use quikcov_common::reader;
+
+// `fixtures/gcc12` holds the notes and counts GCC 12.2 produced for `sample.c`, built with
+// `gcc --coverage -O0` and run as `./sample abc 12e`, `./sample zz` and `./sample`
+
+const GCNO: &[u8] = include_bytes!("fixtures/gcc12/sample.gcno");
+const GCDA: &[u8] = include_bytes!("fixtures/gcc12/sample.gcda");
+
+fn sample_coverage() -> ProgCoverage {
+    let mut builder = FileCovBuilder::new(Gcno::from_slice(GCNO).unwrap());
+    builder.add_gcda(GCDA).unwrap();
+    builder.build().unwrap()
+}
+
+#[test]
+fn reads_gcc12_notes() {
+    // GCC 12 counts record lengths in bytes rather than words
+    let gcno = Gcno::from_slice(GCNO).unwrap();
+    assert_eq!(gcno.version, 122);
+    assert_eq!(gcno.cwd.as_deref(), Some("/tmp/fx"));
+
+    let mut functions: Vec<_> = gcno.functions.iter().map(|function| (function.name.as_str(), function.start_line, function.end_line)).collect();
+    functions.sort();
+    assert_eq!(functions, [("classify", 4, Some(16)), ("main", 23, Some(32)), ("never_called", 18, Some(21))]);
+}
+
+#[test]
+fn reads_gcc12_counts() {
+    let coverage = sample_coverage();
+    let file = &coverage.files["sample.c"];
+
+    let mut functions: Vec<_> = file.fns.keys().map(String::as_str).collect();
+    functions.sort();
+    assert_eq!(functions, ["classify", "main", "never_called"]);
+}
+
+/// The fixture's counts with the little-endian word at `offset` replaced by `value`
+fn patched_gcda(offset: usize, value: u32) -> Vec<u8> {
+    let mut gcda = GCDA.to_vec();
+    gcda[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    gcda
+}
+
+// The counts start with the magic, version, stamp and object checksum, then an 8-byte object
+// summary record and the record of the first function: its tag, length, ident, line checksum and
+// cfg checksum
+const STAMP: usize = 8;
+const OBJECT_CHECKSUM: usize = 12;
+const FIRST_IDENT: usize = 40;
+const FIRST_LINE_CHECKSUM: usize = 44;
+const FIRST_CFG_CHECKSUM: usize = 48;
+
+#[test]
+fn matches_its_own_counts() {
+    let gcno = Gcno::from_slice(GCNO).unwrap();
+    assert_eq!(reader::gcda_stamp(GCDA).unwrap(), gcno.stamp);
+    assert!(gcno.matches_gcda(GCDA).unwrap());
+}
+
+#[test]
+fn ignores_the_object_checksum() {
+    // GCC leaves it out of the notes (writing 0), so only the counts carry it
+    let gcno = Gcno::from_slice(GCNO).unwrap();
+    assert_eq!(gcno.chksum, 0);
+
+    let gcda = patched_gcda(OBJECT_CHECKSUM, 0x1234_5678);
+    assert!(gcno.matches_gcda(&gcda).unwrap());
+    FileCovBuilder::new(gcno).add_gcda(&gcda).unwrap();
+}
+
+#[test]
+fn rejects_counts_of_another_compilation() {
+    let gcno = Gcno::from_slice(GCNO).unwrap();
+    let gcda = patched_gcda(STAMP, gcno.stamp.unwrap() ^ 1);
+
+    assert!(!gcno.matches_gcda(&gcda).unwrap());
+    assert!(matches!(FileCovBuilder::new(gcno).add_gcda(&gcda), Err(reader::Error::Stamp)));
+}
+
+#[test]
+fn rejects_functions_whose_checksums_differ() {
+    for offset in [FIRST_LINE_CHECKSUM, FIRST_CFG_CHECKSUM] {
+        let gcno = Gcno::from_slice(GCNO).unwrap();
+        let word = u32::from_le_bytes(GCDA[offset..offset + 4].try_into().unwrap());
+        let gcda = patched_gcda(offset, word ^ 1);
+
+        assert!(!gcno.matches_gcda(&gcda).unwrap());
+        assert!(matches!(FileCovBuilder::new(gcno).add_gcda(&gcda), Err(reader::Error::Checksum)));
+    }
+}
+
+#[test]
+fn rejects_functions_it_doesnt_know() {
+    let gcno = Gcno::from_slice(GCNO).unwrap();
+    let gcda = patched_gcda(FIRST_IDENT, 0xdead);
+
+    assert!(!gcno.matches_gcda(&gcda).unwrap());
+    assert!(FileCovBuilder::new(gcno).add_gcda(&gcda).is_err());
+}
@@ -0,0 +1,30 @@
+use quikcov_common::gcda_path::{self, Relocation};
+
+// The expected paths below are those GCC 12 and its libgcov produce for the same inputs
+
+#[test]
+fn mangles_separators_and_parent_directories() {
+    assert_eq!(gcda_path::mangle("/tmp/pd/src/../src/sub/t"), "#tmp#pd#src#^#src#sub#t");
+}
+
+#[test]
+fn names_gcda_files_under_the_profile_directory() {
+    assert_eq!(gcda_path::compile_time_paths("/tmp/pd/src/sub/t", None), ["/tmp/pd/src/sub/t.gcda"]);
+    assert_eq!(gcda_path::compile_time_paths("/tmp/pd/src/sub/t", Some("/tmp/pd/prof")), [
+        "/tmp/pd/prof/#tmp#pd#src#sub#t.gcda",
+        "/tmp/pd/prof//tmp/pd/src/sub/t.gcda",
+    ]);
+}
+
+#[test]
+fn relocates_like_libgcov() {
+    let path = "/tmp/pd/prof/#tmp#pd#src#sub#t.gcda";
+
+    assert_eq!(Relocation::new(None, 0).apply(path), path);
+    assert_eq!(Relocation::new(Some("/tmp/gp".to_string()), 0).apply(path), "/tmp/gp/tmp/pd/prof/#tmp#pd#src#sub#t.gcda");
+    assert_eq!(Relocation::new(Some("/tmp/gp/".to_string()), 2).apply(path), "/tmp/gp/prof/#tmp#pd#src#sub#t.gcda");
+    assert_eq!(Relocation::new(Some("rel".to_string()), 1).apply(path), "rel/pd/prof/#tmp#pd#src#sub#t.gcda");
+    // Stripping without a prefix is relative to the cwd, and never strips the file name itself
+    assert_eq!(Relocation::new(None, 1).apply(path), "./pd/prof/#tmp#pd#src#sub#t.gcda");
+    assert_eq!(Relocation::new(None, 9).apply(path), "./#tmp#pd#src#sub#t.gcda");
+}
@@ -0,0 +1,121 @@
+use std::io::{self, Read};
+
+use quikcov_common::protocol::{self, Body, Error, Fallback, FrameReader, Gcda, Message};
+
+fn gcda_message() -> Message {
+    Message {
+        pid: 1234,
+        ppid: 1,
+        body: Body::Gcda(Gcda {
+            filepath: "/build/src/main.gcda".to_string(),
+            data: (0..=255).collect(),
+        }),
+    }
+}
+
+/// Yields at most one byte per `read()`, the way a busy pipe might.
+struct Trickle<'a>(&'a [u8]);
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((&first, rest)) = self.0.split_first() else {
+            return Ok(0)
+        };
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        buf[0] = first;
+        self.0 = rest;
+        Ok(1)
+    }
+}
+
+#[test]
+fn round_trips_every_message_kind() {
+    let messages = vec![
+        Message { pid: 1234, ppid: 1, body: Body::Hello { version: protocol::VERSION } },
+        gcda_message(),
+        Message { pid: 1234, ppid: 1, body: Body::GcdaShm { filepath: "/build/src/main.gcda".to_string(), offset: 4096, len: 512 } },
+        Message { pid: 1234, ppid: 1, body: Body::GcdaBegin { file: 0, filepath: "/build/src/main.gcda".to_string() } },
+        Message { pid: 1234, ppid: 1, body: Body::GcdaChunk { file: 0, seq: 0, data: vec![0xad; 64] } },
+        Message { pid: 1234, ppid: 1, body: Body::GcdaChunkShm { file: 0, seq: 1, offset: 64, len: 64 } },
+        Message { pid: 1234, ppid: 1, body: Body::GcdaEnd { file: 0, chunks: 2 } },
+        Message { pid: 1234, ppid: 1, body: Body::ExitStatus(3) },
+        Message { pid: 1234, ppid: 1, body: Body::ForkserverSpawned { child: 1235 } },
+        Message { pid: 1234, ppid: 1, body: Body::ForkserverExited { child: 1235, wait_status: 0x8b, max_rss: 4096 } },
+        Message { pid: 1234, ppid: 1, body: Body::DumpComplete { seed: Some(7) } },
+        Message { pid: 1234, ppid: 1, body: Body::Error("oops".to_string()) },
+    ];
+
+    let mut stream = Vec::new();
+    for message in &messages {
+        protocol::write_message(&mut stream, message).unwrap();
+    }
+
+    let mut reader = FrameReader::new(stream.as_slice());
+    for message in &messages {
+        assert_eq!(reader.read_message().unwrap().as_ref(), Some(message));
+    }
+    assert!(reader.read_message().unwrap().is_none());
+}
+
+#[test]
+fn frame_is_length_prefixed() {
+    let frame = protocol::encode(&gcda_message()).unwrap();
+    let length = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+    assert_eq!(length, frame.len() - 4);
+}
+
+#[test]
+fn reassembles_frames_split_across_reads() {
+    let mut stream = protocol::encode(&gcda_message()).unwrap();
+    stream.extend(protocol::encode(&gcda_message()).unwrap());
+
+    let mut reader = FrameReader::new(Trickle(&stream));
+    assert_eq!(reader.read_message().unwrap(), Some(gcda_message()));
+    assert_eq!(reader.read_message().unwrap(), Some(gcda_message()));
+    assert!(reader.read_message().unwrap().is_none());
+}
+
+#[test]
+fn truncated_frames_are_errors() {
+    let frame = protocol::encode(&gcda_message()).unwrap();
+
+    for cut in [2, 4, frame.len() - 1] {
+        let mut reader = FrameReader::new(&frame[..cut]);
+        assert!(matches!(reader.read_message(), Err(Error::Truncated)), "cut at {}", cut);
+    }
+}
+
+#[test]
+fn oversized_frames_are_rejected() {
+    let length = (protocol::MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+    let mut reader = FrameReader::new(length.as_slice());
+    assert!(matches!(reader.read_message(), Err(Error::FrameTooLarge(_))));
+}
+
+#[test]
+fn garbage_payload_fails_to_decode() {
+    let mut stream = 4u32.to_be_bytes().to_vec();
+    stream.extend([0xff; 4]);
+    let mut reader = FrameReader::new(stream.as_slice());
+    assert!(matches!(reader.read_message(), Err(Error::Decode(_))));
+}
+
+#[test]
+fn version_check() {
+    assert!(protocol::check_version(protocol::VERSION).is_ok());
+    assert!(matches!(
+        protocol::check_version(protocol::VERSION + 1),
+        Err(Error::VersionMismatch { expected: protocol::VERSION, found }) if found == protocol::VERSION + 1
+    ));
+}
+
+#[test]
+fn fallback_round_trips_through_its_env_value() {
+    for fallback in [Fallback::Passthrough, Fallback::Drop, Fallback::Spool("/tmp/spool".into())] {
+        assert_eq!(fallback.to_string().parse::<Fallback>(), Ok(fallback));
+    }
+    assert!("spool:".parse::<Fallback>().is_err());
+    assert!("abort".parse::<Fallback>().is_err());
+}
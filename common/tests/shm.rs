@@ -0,0 +1,58 @@
+use quikcov_common::shm::{ShmRegion, HEADER_LEN};
+
+/// Backing memory for a region, 8-byte aligned like a mapping
+fn memory(len: usize) -> Vec<u64> {
+    vec![0; len.div_ceil(8)]
+}
+
+#[test]
+fn writes_payloads_one_after_another() {
+    let mut memory = memory(HEADER_LEN + 16);
+    let region = unsafe { ShmRegion::init(memory.as_mut_ptr().cast(), HEADER_LEN + 16) }.unwrap();
+    assert_eq!(region.capacity(), 16);
+
+    assert_eq!(region.write(b"hello"), Some(0));
+    assert_eq!(region.write(b"world!"), Some(5));
+    assert_eq!(region.read(0, 5), Some(&b"hello"[..]));
+    assert_eq!(region.read(5, 6), Some(&b"world!"[..]));
+}
+
+#[test]
+fn refuses_payloads_that_overflow() {
+    let mut memory = memory(HEADER_LEN + 16);
+    let region = unsafe { ShmRegion::init(memory.as_mut_ptr().cast(), HEADER_LEN + 16) }.unwrap();
+
+    assert_eq!(region.write(&[1; 10]), Some(0));
+    assert_eq!(region.write(&[2; 7]), None);
+    // What still fits is written after a refusal
+    assert_eq!(region.write(&[3; 6]), Some(10));
+    assert_eq!(region.write(&[]), Some(16));
+
+    // Reads can't reach past the end either
+    assert_eq!(region.read(10, 7), None);
+    assert_eq!(region.read(u64::MAX, 2), None);
+
+    region.reset();
+    assert_eq!(region.write(&[4; 16]), Some(0));
+}
+
+#[test]
+fn attaches_only_to_formatted_regions() {
+    let mut memory = memory(HEADER_LEN + 32);
+    let base = memory.as_mut_ptr().cast();
+    assert!(unsafe { ShmRegion::attach(base, HEADER_LEN + 32) }.is_none());
+
+    let region = unsafe { ShmRegion::init(base, HEADER_LEN + 32) }.unwrap();
+    let attached = unsafe { ShmRegion::attach(base, HEADER_LEN + 32) }.unwrap();
+    // A different length means a different region
+    assert!(unsafe { ShmRegion::attach(base, HEADER_LEN + 24) }.is_none());
+
+    let offset = attached.write(b"from the target").unwrap();
+    assert_eq!(region.read(offset, 15), Some(&b"from the target"[..]));
+}
+
+#[test]
+fn needs_room_beyond_the_header() {
+    let mut memory = memory(HEADER_LEN);
+    assert!(unsafe { ShmRegion::init(memory.as_mut_ptr().cast(), HEADER_LEN) }.is_none());
+}
@@ -0,0 +1,420 @@
+//! Desocketing in the style of libdesock: servers get their input from stdin while believing it
+//! came in over the network, so that seeds need no real network stack (nor free ports).
+//!
+//! When `QUIKCOV_DESOCK` names a port, any TCP or UDP socket bound to it (over IPv4 or IPv6) is
+//! swapped for one end of a Unix socket pair of the same type, which works with `poll()`, `epoll`
+//! and friends just like the original would:
+//!
+//! - a listening socket has exactly one connection pending, whose data is the whole of stdin,
+//!   followed by the client closing its end;
+//! - a datagram socket receives the whole of stdin as a single datagram.
+//!
+//! If `QUIKCOV_DESOCK_FRAMED` is set, stdin instead holds several messages, each preceded by its
+//! length (a big-endian u32). These are passed on one by one as they arrive, as separate
+//! datagrams or writes by the fake client, keeping the boundaries (and the timing) the runner
+//! delivers them with.
+//!
+//! Whatever the server sends back is discarded. Once the input is used up (the connection gets
+//! closed, a blocking `accept()` waits for a second one, or a datagram socket runs dry), the
+//! process exits, dumping its coverage. Under a forkserver stopping at stdin reads, the first
+//! `accept()` (or `bind()`, for datagram sockets) is where the target forks.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use fxhash::FxBuildHasher;
+use quikcov_common::protocol::{self, Body};
+
+use crate::{close, forkserver, hook_macros, ipc, read, recv, send, state};
+
+/// The port fake clients appear to connect from
+const PEER_PORT: u16 = 49152;
+
+static PORT: OnceLock<Option<u16>> = OnceLock::new();
+static FRAMED: OnceLock<bool> = OnceLock::new();
+static EXITING: AtomicBool = AtomicBool::new(false);
+/// Set once all of stdin has been passed on
+static INPUT_DONE: AtomicBool = AtomicBool::new(false);
+
+pub type Sockets = HashMap<libc::c_int, Socket, FxBuildHasher>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Listener { accepted: bool },
+    Connection,
+    Datagram,
+}
+
+#[derive(Clone, Copy)]
+pub struct Socket {
+    kind: Kind,
+    /// The address the server bound the socket to
+    local: (libc::sockaddr_storage, libc::socklen_t),
+}
+
+fn port() -> Option<u16> {
+    *PORT.get_or_init(|| std::env::var(protocol::DESOCK_ENV).ok()?.parse().ok())
+}
+
+fn framed() -> bool {
+    *FRAMED.get_or_init(|| std::env::var_os(protocol::DESOCK_FRAMED_ENV).is_some())
+}
+
+/// Indicates whether any socket may be desocketed in this process.
+pub fn enabled() -> bool {
+    port().is_some()
+}
+
+fn lookup(fd: libc::c_int) -> Option<Socket> {
+    state::lock(state::desocked()).get(&fd).copied()
+}
+
+/// Indicates whether `fd` was desocketed, so that the options set on it can be ignored.
+pub fn is_desocked(fd: libc::c_int) -> bool {
+    enabled() && lookup(fd).is_some()
+}
+
+/// Indicates whether anything written to `fd` should be discarded.
+pub fn swallows(fd: libc::c_int) -> bool {
+    is_desocked(fd)
+}
+
+/// Called in place of `bind()`, desocketing `fd` if the address is the one of interest. Returns
+/// `false` if the real `bind()` should go ahead.
+///
+/// # Safety
+///
+/// `addr` must point to `addrlen` readable bytes.
+pub unsafe fn bind(fd: libc::c_int, addr: *const libc::sockaddr, addrlen: libc::socklen_t) -> bool {
+    let Some(wanted_port) = port() else {
+        return false
+    };
+    if addr.is_null() {
+        return false
+    }
+
+    let bound_port = match (*addr).sa_family as libc::c_int {
+        libc::AF_INET if addrlen as usize >= std::mem::size_of::<libc::sockaddr_in>() => (*(addr as *const libc::sockaddr_in)).sin_port,
+        libc::AF_INET6 if addrlen as usize >= std::mem::size_of::<libc::sockaddr_in6>() => (*(addr as *const libc::sockaddr_in6)).sin6_port,
+        _ => return false,
+    };
+    if wanted_port != 0 && u16::from_be(bound_port) != wanted_port {
+        return false
+    }
+
+    let mut socket_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut socket_type as *mut libc::c_int as *mut libc::c_void, &mut len) != 0 {
+        return false
+    }
+    let kind = match socket_type {
+        libc::SOCK_STREAM => Kind::Listener { accepted: false },
+        libc::SOCK_DGRAM => Kind::Datagram,
+        _ => return false,
+    };
+
+    if kind == Kind::Datagram {
+        forkserver::on_stdin_read();
+    }
+
+    let Some(peer) = replace(fd, socket_type) else {
+        return false
+    };
+
+    match kind {
+        // The one pending connection makes the listening socket readable
+        Kind::Listener { .. } => {
+            hook_macros::real!(send)(peer, [0u8].as_ptr() as *const libc::c_void, 1, libc::MSG_NOSIGNAL);
+            hook_macros::real!(close)(peer);
+        }
+        _ => feed(peer, Some(libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0))),
+    }
+
+    let mut local: libc::sockaddr_storage = std::mem::zeroed();
+    let len = (addrlen as usize).min(std::mem::size_of_val(&local));
+    std::ptr::copy_nonoverlapping(addr as *const u8, &mut local as *mut libc::sockaddr_storage as *mut u8, len);
+
+    state::lock(state::desocked()).insert(fd, Socket { kind, local: (local, len as libc::socklen_t) });
+    true
+}
+
+/// Swaps the socket behind `fd` for one end of a Unix socket pair, keeping its file status and
+/// descriptor flags, and returns the other end.
+unsafe fn replace(fd: libc::c_int, socket_type: libc::c_int) -> Option<libc::c_int> {
+    let status_flags = libc::fcntl(fd, libc::F_GETFL);
+    let fd_flags = libc::fcntl(fd, libc::F_GETFD);
+
+    let mut pair = [0; 2];
+    if libc::socketpair(libc::AF_UNIX, socket_type | libc::SOCK_CLOEXEC, 0, pair.as_mut_ptr()) != 0 {
+        return None
+    }
+
+    if libc::dup2(pair[0], fd) < 0 {
+        hook_macros::real!(close)(pair[0]);
+        hook_macros::real!(close)(pair[1]);
+        return None
+    }
+    hook_macros::real!(close)(pair[0]);
+
+    libc::fcntl(fd, libc::F_SETFL, status_flags);
+    libc::fcntl(fd, libc::F_SETFD, fd_flags);
+
+    Some(pair[1])
+}
+
+/// Reads the next message from stdin: the whole of it, unless the runner framed several.
+fn next_message(first: bool) -> Option<Vec<u8>> {
+    if !framed() {
+        return first.then(read_stdin)
+    }
+
+    let mut len = [0u8; 4];
+    if !read_exact_stdin(&mut len) {
+        return None
+    }
+    let mut message = vec![0u8; u32::from_be_bytes(len) as usize];
+    read_exact_stdin(&mut message).then_some(message)
+}
+
+fn read_stdin() -> Vec<u8> {
+    let mut seed = Vec::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match unsafe { hook_macros::real!(read)(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+            0 => return seed,
+            n if n > 0 => seed.extend_from_slice(&buf[..n as usize]),
+            _ if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => (),
+            _ => return seed,
+        }
+    }
+}
+
+fn read_exact_stdin(mut buf: &mut [u8]) -> bool {
+    while !buf.is_empty() {
+        match unsafe { hook_macros::real!(read)(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+            n if n > 0 => buf = &mut buf[n as usize..],
+            n if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => (),
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Called in place of `listen()`. Returns `false` if the real `listen()` should go ahead.
+pub fn listen(fd: libc::c_int) -> bool {
+    is_desocked(fd)
+}
+
+/// Called in place of `accept()`, returning the fake connection (or `-1`, with `errno` set) for
+/// desocketed listening sockets and `None` for any other.
+///
+/// # Safety
+///
+/// `addr` and `addrlen` must be null or valid as for `accept()`.
+pub unsafe fn accept(fd: libc::c_int, addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t, flags: libc::c_int) -> Option<libc::c_int> {
+    if !enabled() {
+        return None
+    }
+    let listener = lookup(fd)?;
+    match listener.kind {
+        Kind::Listener { accepted: false } => (),
+        Kind::Listener { accepted: true } => {
+            if libc::fcntl(fd, libc::F_GETFL) & libc::O_NONBLOCK != 0 {
+                *libc::__errno_location() = libc::EAGAIN;
+            } else {
+                // Nobody else is ever going to connect
+                finish();
+                *libc::__errno_location() = libc::ECONNABORTED;
+            }
+            return Some(-1)
+        }
+        _ => {
+            *libc::__errno_location() = libc::EINVAL;
+            return Some(-1)
+        }
+    }
+
+    forkserver::on_stdin_read();
+
+    // Under a forkserver, the byte may already have been taken by an earlier child
+    let mut pending = 0u8;
+    hook_macros::real!(recv)(fd, &mut pending as *mut u8 as *mut libc::c_void, 1, libc::MSG_DONTWAIT);
+
+    let mut pair = [0; 2];
+    if libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0, pair.as_mut_ptr()) != 0 {
+        return Some(-1)
+    }
+    let [connection, peer] = pair;
+
+    if flags & libc::SOCK_CLOEXEC == 0 {
+        libc::fcntl(connection, libc::F_SETFD, 0);
+    }
+    if flags & libc::SOCK_NONBLOCK != 0 {
+        libc::fcntl(connection, libc::F_SETFL, libc::fcntl(connection, libc::F_GETFL) | libc::O_NONBLOCK);
+    }
+
+    {
+        let mut desocked = state::lock(state::desocked());
+        desocked.insert(fd, Socket { kind: Kind::Listener { accepted: true }, ..listener });
+        desocked.insert(connection, Socket { kind: Kind::Connection, ..listener });
+    }
+
+    feed(peer, None);
+    write_address(&peer_address(&listener), addr, addrlen);
+
+    Some(connection)
+}
+
+/// Passes the messages read from stdin on through `peer` on a thread of its own, closing it
+/// once they run out. For a datagram socket, `socket` is a duplicate of the server's end, which is
+/// shut down so that a server already waiting for another datagram gives up.
+fn feed(peer: libc::c_int, socket: Option<libc::c_int>) {
+    std::thread::spawn(move || unsafe {
+        let mut first = true;
+        while let Some(message) = next_message(first) {
+            first = false;
+            if !deliver(peer, &message) {
+                break
+            }
+        }
+
+        INPUT_DONE.store(true, Ordering::Release);
+        if let Some(socket) = socket {
+            libc::shutdown(socket, libc::SHUT_RD);
+            hook_macros::real!(close)(socket);
+        }
+        hook_macros::real!(close)(peer);
+    });
+}
+
+/// Sends `message` through `peer`, returning `false` if the server is gone.
+unsafe fn deliver(peer: libc::c_int, mut message: &[u8]) -> bool {
+    loop {
+        match hook_macros::real!(send)(peer, message.as_ptr() as *const libc::c_void, message.len(), libc::MSG_NOSIGNAL) {
+            // Datagrams are sent whole or not at all
+            n if n >= 0 => {
+                message = &message[n as usize..];
+                if message.is_empty() {
+                    return true
+                }
+            }
+            _ => match std::io::Error::last_os_error() {
+                e if e.kind() == std::io::ErrorKind::Interrupted => (),
+                e if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                    ipc::send(Body::Error(format!("failed to desocket a {}-byte datagram: {}", message.len(), e)));
+                    return true
+                }
+                // The server closed the connection without reading everything
+                _ => return false,
+            },
+        }
+    }
+}
+
+/// Called before anything reads from `fd`, exiting if it's a datagram socket with nothing left
+/// to receive, now or later.
+pub fn before_recv(fd: libc::c_int) {
+    if !enabled() || lookup(fd).map(|socket| socket.kind) != Some(Kind::Datagram) {
+        return
+    }
+
+    // Checked first, as the last datagram may be queued just before
+    let input_done = INPUT_DONE.load(Ordering::Acquire);
+    let mut peek = 0u8;
+    if input_done && unsafe { hook_macros::real!(recv)(fd, &mut peek as *mut u8 as *mut libc::c_void, 0, libc::MSG_PEEK | libc::MSG_DONTWAIT) } < 0 {
+        finish();
+    }
+}
+
+/// Fills in the address the data read from `fd` came from, if `fd` was desocketed. Returns
+/// `false` otherwise.
+///
+/// # Safety
+///
+/// `addr` and `addrlen` must be null or valid as for `getpeername()`.
+pub unsafe fn peer_name(fd: libc::c_int, addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t) -> bool {
+    if !enabled() {
+        return false
+    }
+    let Some(socket) = lookup(fd) else {
+        return false
+    };
+    write_address(&peer_address(&socket), addr, addrlen);
+    true
+}
+
+/// Fills in the address `fd` was bound to, if it was desocketed. Returns `false` otherwise.
+///
+/// # Safety
+///
+/// `addr` and `addrlen` must be null or valid as for `getsockname()`.
+pub unsafe fn sock_name(fd: libc::c_int, addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t) -> bool {
+    if !enabled() {
+        return false
+    }
+    let Some(socket) = lookup(fd) else {
+        return false
+    };
+    write_address(&socket.local, addr, addrlen);
+    true
+}
+
+/// The loopback address of the fake client, in the family of the address `socket` was bound to.
+fn peer_address(socket: &Socket) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let (local, _) = &socket.local;
+    let mut peer: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let len = if local.ss_family as libc::c_int == libc::AF_INET6 {
+        let address = unsafe { &mut *(&mut peer as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+        address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        address.sin6_port = PEER_PORT.to_be();
+        address.sin6_addr.s6_addr[15] = 1;
+        std::mem::size_of::<libc::sockaddr_in6>()
+    } else {
+        let address = unsafe { &mut *(&mut peer as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+        address.sin_family = libc::AF_INET as libc::sa_family_t;
+        address.sin_port = PEER_PORT.to_be();
+        address.sin_addr.s_addr = u32::from(std::net::Ipv4Addr::LOCALHOST).to_be();
+        std::mem::size_of::<libc::sockaddr_in>()
+    };
+
+    (peer, len as libc::socklen_t)
+}
+
+/// Copies `address` out the way the kernel does: truncated to the caller's buffer, with the
+/// full length reported back.
+unsafe fn write_address(address: &(libc::sockaddr_storage, libc::socklen_t), addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t) {
+    if addr.is_null() || addrlen.is_null() {
+        return
+    }
+
+    let (storage, len) = address;
+    let copied = (*addrlen).min(*len) as usize;
+    std::ptr::copy_nonoverlapping(storage as *const libc::sockaddr_storage as *const u8, addr as *mut u8, copied);
+    *addrlen = *len;
+}
+
+/// Called before `fd` is closed, exiting if it was the fake connection.
+pub fn on_close(fd: libc::c_int) {
+    if !enabled() {
+        return
+    }
+
+    let removed = state::lock(state::desocked()).remove(&fd);
+    if removed.is_some_and(|socket| socket.kind == Kind::Connection) {
+        finish();
+    }
+}
+
+/// Called when the process exits by itself, which the hooks must not interfere with.
+pub fn on_exit() {
+    EXITING.store(true, Ordering::Relaxed);
+}
+
+/// Exits now that the server has consumed all of its input.
+fn finish() {
+    if !EXITING.swap(true, Ordering::Relaxed) {
+        unsafe { libc::exit(0) };
+    }
+}
@@ -0,0 +1,35 @@
+//! Makes targets that never exit by themselves (servers, mostly) exit on a signal of the runner's
+//! choosing, so that their coverage gets dumped.
+//!
+//! The handler calls `exit()`, which isn't async-signal-safe; a target interrupted in the middle
+//! of, say, `malloc()` may deadlock, which the runner guards against by killing it eventually.
+//! Targets that install their own handler for the signal keep it.
+
+use quikcov_common::protocol;
+
+use crate::ipc;
+
+/// Installs the handler for the signal named by `QUIKCOV_DUMP_SIGNAL`, if any.
+pub fn install() {
+    let Some(signal) = std::env::var(protocol::DUMP_SIGNAL_ENV).ok().and_then(|signal| signal.parse::<libc::c_int>().ok()) else {
+        return
+    };
+
+    if !ipc::enabled() {
+        return
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_dump_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            ipc::send(protocol::Body::Error(format!("failed to install handler for dump signal {}: {}", signal, std::io::Error::last_os_error())));
+        }
+    }
+}
+
+extern "C" fn on_dump_signal(signal: libc::c_int) {
+    // Exits the way a shell reports death by a signal
+    unsafe { libc::exit(128 + signal) };
+}
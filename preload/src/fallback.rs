@@ -0,0 +1,82 @@
+//! What becomes of captured `.gcda` files that can't be sent to the runner, e.g. because it exited
+//! early and closed its end of the pipe.
+//!
+//! Chosen by `QUIKCOV_FALLBACK` (see [`Fallback`]); files are passed through to where libgcov
+//! meant to write them unless it says otherwise.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use quikcov_common::protocol::{self, Fallback, Gcda};
+
+use crate::{hook_macros, ipc, open};
+
+static FALLBACK: OnceLock<Fallback> = OnceLock::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+fn fallback() -> &'static Fallback {
+    FALLBACK.get_or_init(|| {
+        std::env::var(protocol::FALLBACK_ENV).ok()
+            .and_then(|fallback| fallback.parse().ok())
+            .unwrap_or_default()
+    })
+}
+
+/// Indicates whether unsendable files go where libgcov would have written them anyway, in which
+/// case direct capture can just as well leave them for libgcov to write (and merge) itself.
+pub fn is_passthrough() -> bool {
+    *fallback() == Fallback::Passthrough
+}
+
+/// Applies the configured fallback to a `.gcda` file the runner couldn't be sent.
+pub fn store(gcda: &Gcda) {
+    let Some(fd) = create(&gcda.filepath) else {
+        return count_dropped()
+    };
+
+    if ipc::write_all(fd, &gcda.data).is_err() {
+        count_dropped();
+    }
+    unsafe { libc::close(fd) };
+}
+
+/// Opens wherever the configured fallback stores the `.gcda` file at `filepath`, or returns
+/// `None` (counting the file as dropped) if it's to be discarded.
+pub fn create(filepath: &str) -> Option<libc::c_int> {
+    let path = match fallback() {
+        Fallback::Passthrough => PathBuf::from(filepath),
+        Fallback::Spool(dir) => dir.join(format!("{}.{}", std::process::id(), filepath.replace('/', "#"))),
+        Fallback::Drop => {
+            count_dropped();
+            return None
+        }
+    };
+
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        count_dropped();
+        return None
+    };
+
+    // The real `open()`, so that the file isn't mistaken for one libgcov is writing
+    let fd = unsafe { hook_macros::real!(open)(path.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC, 0o644) };
+    if fd < 0 {
+        count_dropped();
+        return None
+    }
+
+    Some(fd)
+}
+
+/// Records that a `.gcda` file (or what remained of it) was lost.
+pub fn count_dropped() {
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of `.gcda` files this process had to discard.
+#[no_mangle]
+pub extern "C" fn quikcov_dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,140 @@
+//! An AFL-style forkserver that saves the runner from paying for `exec()`, dynamic linking and
+//! program initialization on every seed.
+//!
+//! When enabled through `QUIKCOV_FORKSERVER`, the target runs normally up to the configured stop
+//! point. From there on, the original process only ever forks: each 4-byte request read from the
+//! control pipe produces a child that rewinds stdin and carries on from the stop point, while the
+//! forkserver reports the child's pid and wait status over the IPC pipe. The child's coverage is
+//! captured by the usual `.gcda` interception when it exits. When the runner times seeds out, each
+//! child leads a process group of its own, so that it can be stopped along with anything it spawned.
+//!
+//! Only the thread that reaches the stop point survives in the children, so targets should reach
+//! it before spawning any threads.
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use quikcov_common::protocol::{self, Body};
+
+use crate::ipc;
+
+pub type MainFn = unsafe extern "C" fn(libc::c_int, *mut *mut libc::c_char, *mut *mut libc::c_char) -> libc::c_int;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    StdinRead,
+    Main,
+}
+
+#[derive(Clone, Copy)]
+struct Config {
+    mode: Mode,
+    control_fd: libc::c_int,
+    /// Whether each child leads a process group of its own
+    child_groups: bool,
+}
+
+static CONFIG: OnceLock<Option<Config>> = OnceLock::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+static REAL_MAIN: AtomicUsize = AtomicUsize::new(0);
+
+fn config() -> Option<Config> {
+    *CONFIG.get_or_init(|| {
+        let mode = match std::env::var(protocol::FORKSERVER_ENV).ok()?.as_str() {
+            protocol::FORKSERVER_STDIN_READ => Mode::StdinRead,
+            protocol::FORKSERVER_MAIN => Mode::Main,
+            _ => return None,
+        };
+        let control_fd = std::env::var(protocol::FORKSERVER_FD_ENV).ok()?.parse().ok()?;
+        let child_groups = std::env::var_os(protocol::FORKSERVER_GROUPS_ENV).is_some();
+        ipc::enabled().then_some(Config { mode, control_fd, child_groups })
+    })
+}
+
+/// Called whenever the target is about to read from stdin.
+pub fn on_stdin_read() {
+    if STARTED.load(Ordering::Relaxed) {
+        return
+    }
+
+    if let Some(config @ Config { mode: Mode::StdinRead, .. }) = config() {
+        run(config);
+    }
+}
+
+/// Called from `__libc_start_main()`, returning the `main()` that should be run in its place.
+pub fn on_start_main(main: MainFn) -> MainFn {
+    let config = config();
+
+    // Programs executed by the target (or its children) must not become forkservers themselves.
+    // This is the last point where the environment can be changed without racing other threads.
+    std::env::remove_var(protocol::FORKSERVER_ENV);
+    std::env::remove_var(protocol::FORKSERVER_FD_ENV);
+    std::env::remove_var(protocol::FORKSERVER_GROUPS_ENV);
+
+    match config {
+        Some(Config { mode: Mode::Main, .. }) => {
+            REAL_MAIN.store(main as usize, Ordering::Relaxed);
+            forkserver_main
+        }
+        _ => main,
+    }
+}
+
+unsafe extern "C" fn forkserver_main(argc: libc::c_int, argv: *mut *mut libc::c_char, envp: *mut *mut libc::c_char) -> libc::c_int {
+    if let Some(config) = config() {
+        run(config);
+    }
+
+    let main: MainFn = std::mem::transmute(REAL_MAIN.load(Ordering::Relaxed));
+    main(argc, argv, envp)
+}
+
+/// Serves fork requests until the control pipe closes. Only ever returns in a forked child.
+fn run(Config { control_fd, child_groups, .. }: Config) {
+    if STARTED.swap(true, Ordering::Relaxed) {
+        return
+    }
+
+    loop {
+        let mut request = [0u8; 4];
+        if !ipc::read_exact(control_fd, &mut request) {
+            // The runner is done with us; exit without dumping the forkserver's own coverage
+            unsafe { libc::_exit(0) };
+        }
+
+        match unsafe { libc::fork() } {
+            -1 => {
+                ipc::send(Body::Error(format!("forkserver failed to fork: {}", std::io::Error::last_os_error())));
+                unsafe { libc::_exit(1) };
+            }
+            0 => unsafe {
+                if child_groups {
+                    libc::setpgid(0, 0);
+                }
+                libc::close(control_fd);
+                libc::lseek(libc::STDIN_FILENO, 0, libc::SEEK_SET);
+                return
+            }
+            child => {
+                // Also done here, as the runner may signal the group before the child gets to it
+                if child_groups {
+                    unsafe { libc::setpgid(child, child) };
+                }
+                ipc::send(Body::ForkserverSpawned { child: child as u32 });
+
+                let mut wait_status = 0;
+                let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+                while unsafe { libc::wait4(child, &mut wait_status, 0, &mut usage) } < 0 {
+                    if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+                        break
+                    }
+                }
+
+                // Darwin counts in bytes where everyone else counts in KiB
+                let max_rss = usage.ru_maxrss as u64 / if cfg!(target_vendor = "apple") { 1024 } else { 1 };
+                ipc::send(Body::ForkserverExited { child: child as u32, wait_status, max_rss });
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! Direct capture: walks the `gcov_info` objects registered with libgcov and serializes their
+//! counters into `.gcda` files ourselves, instead of intercepting the file I/O libgcov performs.
+//!
+//! Enabled by `QUIKCOV_CAPTURE=gcov-info`. The counters are captured when the target calls
+//! `exit()` or returns from `main()`; each registered object is then marked as dumped so that
+//! libgcov doesn't go on to write the same coverage to disk.
+//!
+//! The objects are found through libgcov's `__gcov_master`, which the target has to export in its
+//! dynamic symbol table (link with `-rdynamic` or `-Wl,--export-dynamic-symbol=__gcov_master`)
+//! unless it links the static library.
+//! Files that can't be sent while falling back to passthrough are left for libgcov to write too.
+//! Only the `gcov_info` layout of GCC 12 and later is understood. Whenever the master can't be
+//! found or has an unknown layout, libgcov is left to write its files and the usual `open()`/
+//! `write()`/`fclose()` hooks capture them instead.
+
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+use quikcov_common::gcda_path::Relocation;
+use quikcov_common::protocol::{self, Body, Gcda};
+use quikcov_common::reader;
+use quikcov_common::writer::GcdaWriter;
+
+use crate::forkserver::MainFn;
+use crate::{fallback, ipc};
+
+type MergeFn = unsafe extern "C" fn(*mut i64, libc::c_uint);
+
+#[repr(C)]
+struct GcovMaster {
+    version: u32,
+    root: *mut GcovRoot,
+}
+
+#[repr(C)]
+struct GcovRoot {
+    list: *const libc::c_void,
+    /// The `dumped` and `run_counted` bitfields
+    flags: libc::c_uint,
+    next: *mut GcovRoot,
+    prev: *mut GcovRoot,
+}
+
+const ROOT_DUMPED: libc::c_uint = 1 << 0;
+const ROOT_RUN_COUNTED: libc::c_uint = 1 << 1;
+
+/// `struct gcov_info` as of GCC 12, where `N` is the number of counter kinds (`GCOV_COUNTERS`).
+#[repr(C)]
+struct GcovInfo<const N: usize> {
+    version: u32,
+    next: *const GcovInfo<N>,
+    stamp: u32,
+    checksum: u32,
+    filename: *const libc::c_char,
+    merge: [Option<MergeFn>; N],
+    n_functions: libc::c_uint,
+    functions: *const *const GcovFnInfo,
+}
+
+#[repr(C)]
+struct GcovFnInfo {
+    key: *const libc::c_void,
+    ident: u32,
+    lineno_checksum: u32,
+    cfg_checksum: u32,
+    /// One entry for each counter kind with a merge function, in order
+    ctrs: [GcovCtrInfo; 0],
+}
+
+#[repr(C)]
+struct GcovCtrInfo {
+    num: libc::c_uint,
+    values: *mut i64,
+}
+
+// The TOPN and indirect call counters hold pointers to dynamically allocated lists rather than
+// plain values, so they're left out of captured files (quikcov only ever reads arcs anyway)
+const GCOV_COUNTER_V_TOPN: usize = 3;
+const GCOV_COUNTER_V_INDIR: usize = 4;
+
+// Holds the address of `__gcov_master`, as raw pointers can't be shared between threads
+static MASTER: OnceLock<Option<(usize, usize)>> = OnceLock::new();
+
+/// Returns libgcov's master record along with the number of counter kinds of its version, if
+/// direct capture was requested and is possible for this process.
+fn master() -> Option<(&'static GcovMaster, usize)> {
+    let (master, counters) = (*MASTER.get_or_init(|| {
+        if std::env::var(protocol::CAPTURE_ENV).ok()? != protocol::CAPTURE_GCOV_INFO || !ipc::enabled() {
+            return None
+        }
+
+        let master = unsafe { &*find_master()? };
+
+        let counters = match reader::decode_version(master.version) {
+            Ok(120..=139) => 8,
+            Ok(140..=149) => 9,
+            Ok(150..=159) => 10,
+            _ => {
+                ipc::send(Body::Error(format!("direct capture doesn't support gcov version {:#x}; falling back to file I/O", master.version)));
+                return None
+            }
+        };
+
+        Some((master as *const GcovMaster as usize, counters))
+    }))?;
+
+    Some((unsafe { &*(master as *const GcovMaster) }, counters))
+}
+
+#[cfg(not(feature = "wrap"))]
+fn find_master() -> Option<*const GcovMaster> {
+    // Processes without coverage instrumentation (e.g. a shell run by the target) have no master
+    unsafe { crate::symbol::lookup(c"__gcov_master") }
+}
+
+// Statically linked targets have no dynamic symbol table to look the master up in
+#[cfg(feature = "wrap")]
+fn find_master() -> Option<*const GcovMaster> {
+    extern "C" {
+        static __gcov_master: GcovMaster;
+    }
+
+    Some(std::ptr::addr_of!(__gcov_master))
+}
+
+/// Indicates whether coverage is being captured directly from libgcov's counters.
+pub fn enabled() -> bool {
+    master().is_some()
+}
+
+/// Called from `__libc_start_main()`, returning the `main()` that should be run in its place.
+pub fn on_start_main(main: MainFn) -> MainFn {
+    if enabled() {
+        REAL_MAIN.get_or_init(|| main);
+        capturing_main
+    } else {
+        main
+    }
+}
+
+static REAL_MAIN: OnceLock<MainFn> = OnceLock::new();
+
+unsafe extern "C" fn capturing_main(argc: libc::c_int, argv: *mut *mut libc::c_char, envp: *mut *mut libc::c_char) -> libc::c_int {
+    let status = REAL_MAIN.get().unwrap()(argc, argv, envp);
+    capture();
+    status
+}
+
+/// Sends a `.gcda` file for every object registered with libgcov that hasn't been dumped since its
+/// last reset, and marks them as dumped.
+pub fn capture() {
+    match master() {
+        Some((master, 8)) => unsafe { capture_roots::<8>(master) },
+        Some((master, 9)) => unsafe { capture_roots::<9>(master) },
+        Some((master, 10)) => unsafe { capture_roots::<10>(master) },
+        _ => (),
+    }
+}
+
+/// Zeroes every counter registered with libgcov and clears their dumped flags, as `__gcov_reset()`
+/// does.
+pub fn reset() {
+    match master() {
+        Some((master, 8)) => unsafe { reset_roots::<8>(master) },
+        Some((master, 9)) => unsafe { reset_roots::<9>(master) },
+        Some((master, 10)) => unsafe { reset_roots::<10>(master) },
+        _ => (),
+    }
+}
+
+/// Has libgcov dump its counters itself, leaving it to the file I/O hooks to capture them.
+pub fn libgcov_dump() {
+    #[cfg(not(feature = "wrap"))]
+    if let Some(gcov_dump) = unsafe { crate::symbol::lookup::<unsafe extern "C" fn()>(c"__gcov_dump") } {
+        unsafe { gcov_dump() };
+    }
+
+    #[cfg(feature = "wrap")]
+    {
+        extern "C" {
+            fn __gcov_dump();
+        }
+
+        unsafe { __gcov_dump() };
+    }
+}
+
+unsafe fn capture_roots<const N: usize>(master: &GcovMaster) {
+    let mut root = master.root;
+    while let Some(current) = root.as_mut() {
+        if current.flags & ROOT_DUMPED != 0 {
+            root = current.next;
+            continue
+        }
+
+        let mut leave_to_libgcov = false;
+        let mut info = current.list as *const GcovInfo<N>;
+        while let Some(current_info) = info.as_ref() {
+            match serialize(current_info) {
+                Ok(gcda) => if let Err(gcda) = ipc::try_send_gcda(gcda) {
+                    if fallback::is_passthrough() {
+                        leave_to_libgcov = true;
+                    } else {
+                        fallback::store(&gcda);
+                    }
+                }
+                Err(e) => {
+                    ipc::send(Body::Error(format!("failed to serialize gcov_info for {:?}: {:?}", CStr::from_ptr(current_info.filename), e)));
+                }
+            }
+            info = current_info.next;
+        }
+
+        // libgcov dumps whole roots, so those with unsent files are left to it in their entirety
+        if !leave_to_libgcov {
+            current.flags |= ROOT_DUMPED | ROOT_RUN_COUNTED;
+        }
+        root = current.next;
+    }
+}
+
+unsafe fn reset_roots<const N: usize>(master: &GcovMaster) {
+    let mut root = master.root;
+    while let Some(current) = root.as_mut() {
+        let mut info = current.list as *const GcovInfo<N>;
+        while let Some(current_info) = info.as_ref() {
+            for (_, counters) in functions(current_info).flatten() {
+                for (_, ctr) in counters {
+                    std::ptr::write_bytes(ctr.values, 0, ctr.num as usize);
+                }
+            }
+            info = current_info.next;
+        }
+
+        current.flags &= !ROOT_DUMPED;
+        root = current.next;
+    }
+}
+
+/// Iterates over the functions of `info`, yielding `None` for those whose counters belong to
+/// another object and otherwise the function along with each of its counters' kind.
+unsafe fn functions<const N: usize>(info: &GcovInfo<N>) -> impl Iterator<Item = Option<(&GcovFnInfo, impl Iterator<Item = (usize, &GcovCtrInfo)>)>> {
+    (0..info.n_functions as usize).map(move |idx| {
+        let function = (*info.functions.add(idx)).as_ref()?;
+        if function.key != info as *const GcovInfo<N> as *const libc::c_void {
+            return None
+        }
+
+        let ctrs = function.ctrs.as_ptr();
+        let counters = info.merge.iter()
+            .enumerate()
+            .filter(|(_, merge)| merge.is_some())
+            .enumerate()
+            .map(move |(ctr_idx, (kind, _))| (kind, &*ctrs.add(ctr_idx)));
+
+        Some((function, counters))
+    })
+}
+
+/// Returns the path libgcov would have written the `.gcda` file of `info` to.
+unsafe fn gcda_filepath<const N: usize>(info: &GcovInfo<N>) -> String {
+    static RELOCATION: OnceLock<Relocation> = OnceLock::new();
+
+    let filename = CStr::from_ptr(info.filename).to_string_lossy();
+    let relocated = RELOCATION.get_or_init(Relocation::from_env).apply(&filename);
+    crate::gcda_filepath(&relocated)
+}
+
+unsafe fn serialize<const N: usize>(info: &GcovInfo<N>) -> Result<Gcda, reader::Error> {
+    let mut writer = GcdaWriter::new(info.version, info.stamp, info.checksum)?;
+
+    let sum_max = functions(info)
+        .flatten()
+        .flat_map(|(_, counters)| counters)
+        .filter(|(kind, _)| *kind == 0)
+        .flat_map(|(_, ctr)| std::slice::from_raw_parts(ctr.values, ctr.num as usize))
+        .max()
+        .copied()
+        .unwrap_or(0);
+    writer.object_summary(1, sum_max as u32);
+
+    for function in functions(info) {
+        let Some((function, counters)) = function else {
+            writer.empty_function();
+            continue
+        };
+
+        writer.function(function.ident, function.lineno_checksum, function.cfg_checksum);
+        for (kind, ctr) in counters {
+            if kind == GCOV_COUNTER_V_TOPN || kind == GCOV_COUNTER_V_INDIR {
+                continue
+            }
+            let values = std::slice::from_raw_parts(ctr.values as *const u64, ctr.num as usize);
+            writer.counters(kind as u32, values);
+        }
+    }
+
+    Ok(Gcda {
+        filepath: gcda_filepath(info),
+        data: writer.finish(),
+    })
+}
@@ -2,13 +2,19 @@
 
 use libc::{c_char, c_void};
 
-#[link(name = "dl")]
+// musl provides `dlsym()` from libc itself, with at most an empty libdl for compatibility
+#[cfg_attr(not(target_env = "musl"), link(name = "dl"))]
 extern "C" {
     fn dlsym(handle: *const c_void, symbol: *const c_char) -> *const c_void;
 }
 
 const RTLD_NEXT: *const c_void = -1isize as *const c_void;
 
+/// Looks up the next definition of `symbol` after this library's own.
+///
+/// # Safety
+///
+/// `symbol` must be nul-terminated.
 pub unsafe fn dlsym_next(symbol: &'static str) -> *const u8 {
     let ptr = dlsym(RTLD_NEXT, symbol.as_ptr() as *const c_char);
     if ptr.is_null() {
@@ -25,26 +31,23 @@ macro_rules! hook {
         static $real_fn: $real_fn = $real_fn {__private_field: ()};
 
         impl $real_fn {
-            fn get(&self) -> unsafe extern fn ( $($v : $t),* ) -> $r {
-                use ::std::sync::Once;
+            fn get(&self) -> unsafe extern "C" fn ( $($v : $t),* ) -> $r {
+                static REAL: ::std::sync::OnceLock<usize> = ::std::sync::OnceLock::new();
 
-                static mut REAL: *const u8 = 0 as *const u8;
-                static mut ONCE: Once = Once::new();
-
-                unsafe {
-                    ONCE.call_once(|| {
-                        REAL = $crate::hook_macros::ld_preload::dlsym_next(concat!(stringify!($real_fn), "\0"));
-                    });
-                    ::std::mem::transmute(REAL)
-                }
+                let real = *REAL.get_or_init(|| unsafe {
+                    $crate::hook_macros::ld_preload::dlsym_next(concat!(stringify!($real_fn), "\0")) as usize
+                });
+                unsafe { ::std::mem::transmute::<usize, unsafe extern "C" fn ( $($v : $t),* ) -> $r>(real) }
             }
 
+            #[allow(clippy::missing_safety_doc)]
             #[no_mangle]
-            pub unsafe extern fn $real_fn ( $($v : $t),* ) -> $r {
+            pub unsafe extern "C" fn $real_fn ( $($v : $t),* ) -> $r {
                 ::std::panic::catch_unwind(|| $hook_fn ( $($v),* )).unwrap_or_else(|_| std::process::abort() )
             }
         }
 
+        #[allow(clippy::missing_safety_doc)]
         pub unsafe fn $hook_fn ( $($v : $t),* ) -> $r {
             $body
         }
@@ -0,0 +1,49 @@
+// Hooks for targets that link the static library directly rather than loading it through
+// LD_PRELOAD. Each hook is exported both as `quikcov_<fn>` and as `__wrap_<fn>`, and reaches the
+// original through `__real_<fn>`; the linker only routes calls through these when passed
+// `-Wl,--wrap=<fn>` (see `preload/wrap.rsp`).
+
+macro_rules! hook {
+    (unsafe fn $real_fn:ident ( $($v:ident : $t:ty),* ) -> $r:ty => $hook_fn:ident $body:block) => {
+        #[allow(non_camel_case_types)]
+        pub struct $real_fn {__private_field: ()}
+        #[allow(non_upper_case_globals)]
+        static $real_fn: $real_fn = $real_fn {__private_field: ()};
+
+        impl $real_fn {
+            fn get(&self) -> unsafe extern "C" fn ( $($v : $t),* ) -> $r {
+                extern "C" {
+                    #[link_name = concat!("__real_", stringify!($real_fn))]
+                    fn real ( $($v : $t),* ) -> $r;
+                }
+
+                real
+            }
+
+            #[allow(clippy::missing_safety_doc)]
+            #[export_name = concat!("__wrap_", stringify!($real_fn))]
+            pub unsafe extern "C" fn wrap ( $($v : $t),* ) -> $r {
+                $hook_fn ( $($v),* )
+            }
+        }
+
+        // No `catch_unwind()` here: in static binaries `__libc_start_main()` is hooked before libc
+        // has applied its IFUNC relocations, so even the `memcpy()` moving a closure would crash.
+        // Panics abort regardless, as they can't unwind out of an `extern "C"` function.
+        #[allow(clippy::missing_safety_doc)]
+        #[no_mangle]
+        pub unsafe extern "C" fn $hook_fn ( $($v : $t),* ) -> $r {
+            $body
+        }
+    };
+}
+
+pub(crate) use hook;
+
+macro_rules! real {
+    ($real_fn:ident) => {
+        $real_fn.get()
+    };
+}
+
+pub(crate) use real;
@@ -0,0 +1,170 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use quikcov_common::protocol::{self, Body, Gcda, Message};
+
+use crate::{fallback, hook_macros, read, state, write};
+
+static ENABLED: OnceLock<bool> = OnceLock::new();
+static HELLO_PID: AtomicU32 = AtomicU32::new(0);
+static BROKEN: AtomicBool = AtomicBool::new(false);
+
+/// Indicates whether this process is talking to a runner that understands our protocol.
+///
+/// Processes that inherited `LD_PRELOAD` without the runner's pipe, or whose runner speaks a
+/// different protocol version, leave all file I/O untouched.
+pub fn enabled() -> bool {
+    *ENABLED.get_or_init(|| {
+        let Ok(pipe_str) = std::env::var(protocol::PIPE_FD_ENV) else {
+            return false
+        };
+
+        let Ok(pipe_fd) = pipe_str.parse::<libc::c_int>() else {
+            return false
+        };
+
+        // Make sure the descriptor wasn't closed and reused for something other than our pipe
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(pipe_fd, &mut stat) } != 0 || (stat.st_mode & libc::S_IFMT) != libc::S_IFIFO {
+            return false
+        }
+
+        if let Some(version) = std::env::var(protocol::VERSION_ENV).ok().and_then(|v| v.parse().ok()) {
+            if let Err(e) = protocol::check_version(version) {
+                send(Body::Error(format!("quikcov preload disabled: {}", e)));
+                return false
+            }
+        }
+
+        true
+    })
+}
+
+/// Sends `body` to the runner, preceded by a `Hello` if this is the first message from this process.
+///
+/// Returns `false` if the message couldn't be sent. Once a write to the pipe has failed, the
+/// stream may hold a partial frame, so nothing more is sent from this process.
+pub fn send(body: Body) -> bool {
+    try_send(body).is_ok()
+}
+
+/// Sends a captured `.gcda` file, placing its contents in shared memory when there's room. Files
+/// that can't be sent are handed to the configured fallback.
+pub fn send_gcda(gcda: Gcda) {
+    if let Err(gcda) = try_send_gcda(gcda) {
+        fallback::store(&gcda);
+    }
+}
+
+/// Like [`send_gcda()`], but hands the file back if it couldn't be sent.
+pub fn try_send_gcda(gcda: Gcda) -> Result<(), Gcda> {
+    if let Some(offset) = state::shm_region().and_then(|region| region.write(&gcda.data)) {
+        let shm_body = Body::GcdaShm {
+            filepath: gcda.filepath.clone(),
+            offset,
+            len: gcda.data.len() as u64,
+        };
+        try_send(shm_body).map_err(|_| gcda)
+    } else {
+        try_send(Body::Gcda(gcda)).map_err(|body| match body {
+            Body::Gcda(gcda) => gcda,
+            _ => unreachable!(),
+        })
+    }
+}
+
+/// Like [`send()`], but hands `body` back if it couldn't be sent.
+fn try_send(body: Body) -> Result<(), Body> {
+    let Some(ipc_writer) = state::ipc_writer() else {
+        return Err(body)
+    };
+
+    let (pid, ppid) = state::process_ids();
+
+    let ipc_writer = state::lock(ipc_writer);
+    if BROKEN.load(Ordering::Relaxed) {
+        return Err(body)
+    }
+
+    if HELLO_PID.load(Ordering::Relaxed) != pid {
+        if !write_message(*ipc_writer, &Message { pid, ppid, body: Body::Hello { version: protocol::VERSION } }) {
+            BROKEN.store(true, Ordering::Relaxed);
+            return Err(body)
+        }
+        HELLO_PID.store(pid, Ordering::Relaxed);
+    }
+
+    let message = Message { pid, ppid, body };
+    if !write_message(*ipc_writer, &message) {
+        BROKEN.store(true, Ordering::Relaxed);
+        return Err(message.body)
+    }
+    drop(ipc_writer);
+
+    Ok(())
+}
+
+/// Fills `buf` from a control pipe the runner writes to, returning `false` if the pipe closed first.
+pub fn read_exact(fd: libc::c_int, buf: &mut [u8]) -> bool {
+    let mut total_read = 0;
+    while total_read < buf.len() {
+        match unsafe { hook_macros::real!(read)(fd, buf[total_read..].as_mut_ptr() as *mut libc::c_void, buf.len() - total_read) } {
+            ..=-1 if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => continue,
+            ..=0 => return false,
+            new_read => total_read += new_read as usize,
+        }
+    }
+    true
+}
+
+/// Writes all of `bytes` to `fd`, without letting a closed pipe raise `SIGPIPE` in the target.
+pub fn write_all(fd: libc::c_int, bytes: &[u8]) -> std::io::Result<()> {
+    unsafe {
+        let mut sigpipe: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut sigpipe);
+        libc::sigaddset(&mut sigpipe, libc::SIGPIPE);
+
+        let mut old_mask: libc::sigset_t = std::mem::zeroed();
+        libc::pthread_sigmask(libc::SIG_BLOCK, &sigpipe, &mut old_mask);
+
+        // A SIGPIPE already pending belongs to the target, so it mustn't be consumed below
+        let mut pending: libc::sigset_t = std::mem::zeroed();
+        libc::sigpending(&mut pending);
+        let already_pending = libc::sigismember(&pending, libc::SIGPIPE) == 1;
+
+        let mut result = Ok(());
+        let mut total_written = 0;
+        while total_written < bytes.len() {
+            match hook_macros::real!(write)(fd, bytes[total_written..].as_ptr() as *const libc::c_void, bytes.len() - total_written) {
+                ..=-1 => match std::io::Error::last_os_error() {
+                    e if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    e => {
+                        result = Err(e);
+                        break
+                    }
+                }
+                0 => {
+                    result = Err(std::io::ErrorKind::WriteZero.into());
+                    break
+                }
+                new_written => total_written += new_written as usize,
+            }
+        }
+
+        if result.as_ref().is_err_and(|e| e.raw_os_error() == Some(libc::EPIPE)) && !already_pending {
+            let no_wait = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            libc::sigtimedwait(&sigpipe, std::ptr::null_mut(), &no_wait);
+        }
+
+        libc::pthread_sigmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+        result
+    }
+}
+
+fn write_message(ipc_fd: libc::c_int, message: &Message) -> bool {
+    let Ok(message_bytes) = protocol::encode(message) else {
+        return false
+    };
+
+    write_all(ipc_fd, &message_bytes).is_ok()
+}
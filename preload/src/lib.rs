@@ -1,15 +1,40 @@
+//! Captures the coverage of a target program and reports it to the `quikcov` runner.
+//!
+//! Normally loaded into the target through `LD_PRELOAD`. Targets where that can't work (static
+//! binaries, setuid helpers) can instead link the static library built with the `wrap` feature,
+//! wrapping the hooked functions through the linker and explicitly pulling in a function libgcov
+//! only references weakly:
+//!
+//! ```text
+//! cargo build -p quikcov-preload --features wrap
+//! gcc --coverage ... @preload/wrap.rsp target/debug/libquikcov_preload.a -lpthread -ldl -lm
+//! ```
+//!
+//! Either way, the target may also call `quikcov_dump()` to send its coverage at a point of its
+//! choosing.
+
 //#![feature(c_variadic)]
 
 use std::ffi::CStr;
 
-use state::Gcda;
+use quikcov_common::protocol::Body;
+
+use stream::GcdaStream;
 
 extern crate libc;
 
+#[cfg(target_os = "linux")]
+mod desock;
+mod dump_signal;
+mod fallback;
+mod forkserver;
+mod gcov_info;
 mod hook_macros;
+mod ipc;
+mod persistent;
 mod state;
-
-pub const QUIKCOV_PIPE_ENV: &str = "QUIKCOV_LDPRELOAD_PIPE_FD";
+mod stream;
+mod symbol;
 
 hook_macros::hook! {
     unsafe fn open(
@@ -19,24 +44,15 @@ hook_macros::hook! {
     ) -> libc::c_int => quikcov_open {
         let fd = hook_macros::real!(open)(pathname, flags, mode);
 
-        if fd >= 0 {
+        if fd >= 0 && ipc::enabled() {
             let path_cstr = unsafe { CStr::from_ptr(pathname) };
             let len = path_cstr.to_bytes().len();
 
             let is_gcda = path_cstr.to_bytes().get(len.saturating_sub(5)..).map(|suffix| suffix == b".gcda".as_slice()).unwrap_or(false);
 
             if is_gcda {
-                let mut filepath = path_cstr.to_str().unwrap().to_string();
-                if path_cstr.to_bytes().get(..15).map(|prefix| prefix == b"/proc/self/cwd/".as_slice()).unwrap_or(false) {
-                    let cwd = std::env::current_dir().unwrap();
-                    filepath = format!("{}/{}", cwd.to_str().unwrap(), &path_cstr.to_str().unwrap()[15..]);
-                }
-
-                let mut gcda_files = state::gcda_files().lock().unwrap();
-                gcda_files.insert(fd, Gcda {
-                    filepath,
-                    data: Vec::new(),
-                });
+                let mut gcda_files = state::lock(state::gcda_files());
+                gcda_files.insert(fd, GcdaStream::new(gcda_filepath(&path_cstr.to_string_lossy())));
                 drop(gcda_files);
             }
         }
@@ -45,6 +61,14 @@ hook_macros::hook! {
     }
 }
 
+/// Resolves the path libgcov writes a .gcda file to into the one reported to the runner.
+fn gcda_filepath(filepath: &str) -> String {
+    match (filepath.strip_prefix("/proc/self/cwd/"), std::env::current_dir()) {
+        (Some(relative), Ok(cwd)) => format!("{}/{}", cwd.to_string_lossy(), relative),
+        _ => filepath.to_string(),
+    }
+}
+
 hook_macros::hook! {
     unsafe fn fdopen(
         fd: libc::c_int,
@@ -52,9 +76,9 @@ hook_macros::hook! {
     ) -> *mut libc::FILE => quikcov_fdopen {
         let file = hook_macros::real!(fdopen)(fd, mode);
 
-        if file != std::ptr::null_mut() {
+        if !file.is_null() {
             let file_ptr_value = file as usize;
-            let mut fd_map = state::fd_map().lock().unwrap();
+            let mut fd_map = state::lock(state::fd_map());
             fd_map.insert(file_ptr_value, fd);
             drop(fd_map);
         }
@@ -69,9 +93,14 @@ hook_macros::hook! {
         buf: *const libc::c_void,
         count: libc::size_t
     ) -> libc::ssize_t => quikcov_write {
-        let mut gcda_files = state::gcda_files().lock().unwrap();
+        #[cfg(target_os = "linux")]
+        if desock::swallows(fd) {
+            return count as isize
+        }
+
+        let mut gcda_files = state::lock(state::gcda_files());
         if let Some(gcda_file) = gcda_files.get_mut(&fd) {
-            gcda_file.data.extend_from_slice(std::slice::from_raw_parts(buf as *const u8, count));
+            gcda_file.write(std::slice::from_raw_parts(buf as *const u8, count));
             drop(gcda_files);
             count as isize
         } else {
@@ -89,12 +118,12 @@ hook_macros::hook! {
         nmemb: libc::size_t,
         stream: *mut libc::FILE
     ) -> libc::size_t => quikcov_fwrite {
-        let fd_map = state::fd_map().lock().unwrap();
+        let fd_map = state::lock(state::fd_map());
         if let Some(&fd) = fd_map.get(&(stream as usize)) {
             drop(fd_map);
-            let mut gcda_files = state::gcda_files().lock().unwrap();
+            let mut gcda_files = state::lock(state::gcda_files());
             if let Some(gcda_file) = gcda_files.get_mut(&fd) {
-                gcda_file.data.extend_from_slice(std::slice::from_raw_parts(ptr as *const u8, size * nmemb));
+                gcda_file.write(std::slice::from_raw_parts(ptr as *const u8, size * nmemb));
                 drop(gcda_files);
                 return nmemb as usize
             } else {
@@ -111,41 +140,14 @@ hook_macros::hook! {
     unsafe fn fclose(
         stream: *mut libc::FILE
     ) -> libc::c_int => quikcov_fclose {
-        let mut fd_map = state::fd_map().lock().unwrap();
+        let mut fd_map = state::lock(state::fd_map());
         if let Some(fd) = fd_map.remove(&(stream as usize)) {
             drop(fd_map);
 
-            let mut gcda_files = state::gcda_files().lock().unwrap();
+            let mut gcda_files = state::lock(state::gcda_files());
             if let Some(gcda_file) = gcda_files.remove(&fd) {
                 drop(gcda_files);
-                if !gcda_file.data.is_empty() {
-                    let mut message_bytes = vec![0u8];
-                    let gcda_bytes = postcard::to_stdvec(&gcda_file).unwrap();
-                    message_bytes.extend(&(gcda_bytes.len() as u32).to_be_bytes());
-                    message_bytes.extend(gcda_bytes);
-
-                    let ipc_writer = state::ipc_writer().lock().unwrap();
-
-                    let mut total_written = 0;
-                    while total_written < message_bytes.len() {
-                        match hook_macros::real!(write)(*ipc_writer, message_bytes[total_written..].as_ptr() as *const libc::c_void, message_bytes[total_written..].len()) {
-                            ..=-1 => match *libc::__errno_location() {
-                                libc::EINTR => continue,
-                                e => {
-                                    println!("quikcov write pipe error while writing: {} ({})", e, std::io::Error::from_raw_os_error(e));
-                                    std::process::abort();
-                                }
-                            }
-                            0 => {
-                                println!("quikcov write pipe closed--aborting...");
-                                std::process::abort();
-                            }
-                            new_written => total_written += new_written as usize,
-                        }
-                    }
-
-                    drop(ipc_writer);
-                }
+                gcda_file.finish();
             } else {
                 drop(gcda_files);
             }
@@ -156,3 +158,409 @@ hook_macros::hook! {
         hook_macros::real!(fclose)(stream)
     }
 }
+
+#[cfg(all(target_os = "linux", not(target_env = "musl")))]
+hook_macros::hook! {
+    unsafe fn __libc_start_main(
+        main: forkserver::MainFn,
+        argc: libc::c_int,
+        argv: *mut *mut libc::c_char,
+        init: *mut libc::c_void,
+        fini: *mut libc::c_void,
+        rtld_fini: *mut libc::c_void,
+        stack_end: *mut libc::c_void
+    ) -> libc::c_int => quikcov_libc_start_main {
+        // Nothing else is safe to do until libc has initialized (in static binaries, not even TLS)
+        REAL_MAIN.store(main as usize, std::sync::atomic::Ordering::Relaxed);
+        hook_macros::real!(__libc_start_main)(start_main, argc, argv, init, fini, rtld_fini, stack_end)
+    }
+}
+
+// musl's variant takes no `stack_end`
+#[cfg(all(target_os = "linux", target_env = "musl"))]
+hook_macros::hook! {
+    unsafe fn __libc_start_main(
+        main: forkserver::MainFn,
+        argc: libc::c_int,
+        argv: *mut *mut libc::c_char,
+        init: *mut libc::c_void,
+        fini: *mut libc::c_void,
+        ldso_fini: *mut libc::c_void
+    ) -> libc::c_int => quikcov_libc_start_main {
+        REAL_MAIN.store(main as usize, std::sync::atomic::Ordering::Relaxed);
+        hook_macros::real!(__libc_start_main)(start_main, argc, argv, init, fini, ldso_fini)
+    }
+}
+
+#[cfg(target_os = "linux")]
+static REAL_MAIN: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(target_os = "linux")]
+unsafe extern "C" fn start_main(argc: libc::c_int, argv: *mut *mut libc::c_char, envp: *mut *mut libc::c_char) -> libc::c_int {
+    let main: forkserver::MainFn = std::mem::transmute(REAL_MAIN.load(std::sync::atomic::Ordering::Relaxed));
+    dump_signal::install();
+    let main = persistent::on_start_main(forkserver::on_start_main(gcov_info::on_start_main(main)));
+    main(argc, argv, envp)
+}
+
+// The stdin reads below only matter as potential forkserver stop points
+
+hook_macros::hook! {
+    unsafe fn read(
+        fd: libc::c_int,
+        buf: *mut libc::c_void,
+        count: libc::size_t
+    ) -> libc::ssize_t => quikcov_read {
+        if fd == libc::STDIN_FILENO {
+            forkserver::on_stdin_read();
+        }
+        #[cfg(target_os = "linux")]
+        desock::before_recv(fd);
+        hook_macros::real!(read)(fd, buf, count)
+    }
+}
+
+hook_macros::hook! {
+    unsafe fn fread(
+        ptr: *mut libc::c_void,
+        size: libc::size_t,
+        nmemb: libc::size_t,
+        stream: *mut libc::FILE
+    ) -> libc::size_t => quikcov_fread {
+        if libc::fileno(stream) == libc::STDIN_FILENO {
+            forkserver::on_stdin_read();
+        }
+        hook_macros::real!(fread)(ptr, size, nmemb, stream)
+    }
+}
+
+hook_macros::hook! {
+    unsafe fn fgets(
+        s: *mut libc::c_char,
+        size: libc::c_int,
+        stream: *mut libc::FILE
+    ) -> *mut libc::c_char => quikcov_fgets {
+        if libc::fileno(stream) == libc::STDIN_FILENO {
+            forkserver::on_stdin_read();
+        }
+        hook_macros::real!(fgets)(s, size, stream)
+    }
+}
+
+hook_macros::hook! {
+    unsafe fn fgetc(
+        stream: *mut libc::FILE
+    ) -> libc::c_int => quikcov_fgetc {
+        if libc::fileno(stream) == libc::STDIN_FILENO {
+            forkserver::on_stdin_read();
+        }
+        hook_macros::real!(fgetc)(stream)
+    }
+}
+
+hook_macros::hook! {
+    unsafe fn getc(
+        stream: *mut libc::FILE
+    ) -> libc::c_int => quikcov_getc {
+        if libc::fileno(stream) == libc::STDIN_FILENO {
+            forkserver::on_stdin_read();
+        }
+        hook_macros::real!(getc)(stream)
+    }
+}
+
+hook_macros::hook! {
+    unsafe fn getchar(
+    ) -> libc::c_int => quikcov_getchar {
+        forkserver::on_stdin_read();
+        hook_macros::real!(getchar)()
+    }
+}
+
+hook_macros::hook! {
+    unsafe fn getline(
+        lineptr: *mut *mut libc::c_char,
+        n: *mut libc::size_t,
+        stream: *mut libc::FILE
+    ) -> libc::ssize_t => quikcov_getline {
+        if libc::fileno(stream) == libc::STDIN_FILENO {
+            forkserver::on_stdin_read();
+        }
+        hook_macros::real!(getline)(lineptr, n, stream)
+    }
+}
+
+// Desocketing (see `desock`); descriptors that weren't desocketed are passed through untouched
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn bind(
+        sockfd: libc::c_int,
+        addr: *const libc::sockaddr,
+        addrlen: libc::socklen_t
+    ) -> libc::c_int => quikcov_bind {
+        if desock::bind(sockfd, addr, addrlen) {
+            return 0
+        }
+        hook_macros::real!(bind)(sockfd, addr, addrlen)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn listen(
+        sockfd: libc::c_int,
+        backlog: libc::c_int
+    ) -> libc::c_int => quikcov_listen {
+        if desock::listen(sockfd) {
+            return 0
+        }
+        hook_macros::real!(listen)(sockfd, backlog)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn accept(
+        sockfd: libc::c_int,
+        addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t
+    ) -> libc::c_int => quikcov_accept {
+        match desock::accept(sockfd, addr, addrlen, 0) {
+            Some(fd) => fd,
+            None => hook_macros::real!(accept)(sockfd, addr, addrlen),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn accept4(
+        sockfd: libc::c_int,
+        addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t,
+        flags: libc::c_int
+    ) -> libc::c_int => quikcov_accept4 {
+        match desock::accept(sockfd, addr, addrlen, flags) {
+            Some(fd) => fd,
+            None => hook_macros::real!(accept4)(sockfd, addr, addrlen, flags),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn recv(
+        sockfd: libc::c_int,
+        buf: *mut libc::c_void,
+        len: libc::size_t,
+        flags: libc::c_int
+    ) -> libc::ssize_t => quikcov_recv {
+        desock::before_recv(sockfd);
+        hook_macros::real!(recv)(sockfd, buf, len, flags)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn recvfrom(
+        sockfd: libc::c_int,
+        buf: *mut libc::c_void,
+        len: libc::size_t,
+        flags: libc::c_int,
+        src_addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t
+    ) -> libc::ssize_t => quikcov_recvfrom {
+        desock::before_recv(sockfd);
+        let received = hook_macros::real!(recvfrom)(sockfd, buf, len, flags, src_addr, addrlen);
+        if received >= 0 {
+            desock::peer_name(sockfd, src_addr, addrlen);
+        }
+        received
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn recvmsg(
+        sockfd: libc::c_int,
+        msg: *mut libc::msghdr,
+        flags: libc::c_int
+    ) -> libc::ssize_t => quikcov_recvmsg {
+        desock::before_recv(sockfd);
+        let received = hook_macros::real!(recvmsg)(sockfd, msg, flags);
+        if received >= 0 && !msg.is_null() {
+            desock::peer_name(sockfd, (*msg).msg_name as *mut libc::sockaddr, &mut (*msg).msg_namelen);
+        }
+        received
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn send(
+        sockfd: libc::c_int,
+        buf: *const libc::c_void,
+        len: libc::size_t,
+        flags: libc::c_int
+    ) -> libc::ssize_t => quikcov_send {
+        if desock::swallows(sockfd) {
+            return len as isize
+        }
+        hook_macros::real!(send)(sockfd, buf, len, flags)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn sendto(
+        sockfd: libc::c_int,
+        buf: *const libc::c_void,
+        len: libc::size_t,
+        flags: libc::c_int,
+        dest_addr: *const libc::sockaddr,
+        addrlen: libc::socklen_t
+    ) -> libc::ssize_t => quikcov_sendto {
+        if desock::swallows(sockfd) {
+            return len as isize
+        }
+        hook_macros::real!(sendto)(sockfd, buf, len, flags, dest_addr, addrlen)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn sendmsg(
+        sockfd: libc::c_int,
+        msg: *const libc::msghdr,
+        flags: libc::c_int
+    ) -> libc::ssize_t => quikcov_sendmsg {
+        if desock::swallows(sockfd) && !msg.is_null() {
+            return iov_len((*msg).msg_iov, (*msg).msg_iovlen as libc::c_int)
+        }
+        hook_macros::real!(sendmsg)(sockfd, msg, flags)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn writev(
+        fd: libc::c_int,
+        iov: *const libc::iovec,
+        iovcnt: libc::c_int
+    ) -> libc::ssize_t => quikcov_writev {
+        if desock::swallows(fd) {
+            return iov_len(iov, iovcnt)
+        }
+        hook_macros::real!(writev)(fd, iov, iovcnt)
+    }
+}
+
+/// The total length of the buffers in `iov`.
+#[cfg(target_os = "linux")]
+unsafe fn iov_len(iov: *const libc::iovec, iovcnt: libc::c_int) -> libc::ssize_t {
+    if iov.is_null() || iovcnt <= 0 {
+        return 0
+    }
+    std::slice::from_raw_parts(iov, iovcnt as usize).iter().map(|buf| buf.iov_len as libc::ssize_t).sum()
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn setsockopt(
+        sockfd: libc::c_int,
+        level: libc::c_int,
+        optname: libc::c_int,
+        optval: *const libc::c_void,
+        optlen: libc::socklen_t
+    ) -> libc::c_int => quikcov_setsockopt {
+        // Most options make no sense for (or are refused by) Unix sockets
+        if desock::is_desocked(sockfd) {
+            return 0
+        }
+        hook_macros::real!(setsockopt)(sockfd, level, optname, optval, optlen)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn getsockname(
+        sockfd: libc::c_int,
+        addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t
+    ) -> libc::c_int => quikcov_getsockname {
+        if desock::sock_name(sockfd, addr, addrlen) {
+            return 0
+        }
+        hook_macros::real!(getsockname)(sockfd, addr, addrlen)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn getpeername(
+        sockfd: libc::c_int,
+        addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t
+    ) -> libc::c_int => quikcov_getpeername {
+        if desock::peer_name(sockfd, addr, addrlen) {
+            return 0
+        }
+        hook_macros::real!(getpeername)(sockfd, addr, addrlen)
+    }
+}
+
+#[cfg(target_os = "linux")]
+hook_macros::hook! {
+    unsafe fn close(
+        fd: libc::c_int
+    ) -> libc::c_int => quikcov_close {
+        desock::on_close(fd);
+        hook_macros::real!(close)(fd)
+    }
+}
+
+hook_macros::hook! {
+    unsafe fn exit(
+        status: libc::c_int
+    ) -> () => quikcov_exit {
+        // Coverage is dumped here (direct capture) or by the real `exit()`, so this always precedes
+        // the process's .gcda files
+        #[cfg(target_os = "linux")]
+        desock::on_exit();
+
+        if ipc::enabled() {
+            ipc::send(Body::ExitStatus(status));
+            gcov_info::capture();
+        }
+
+        hook_macros::real!(exit)(status)
+    }
+}
+
+/// Sends the coverage gathered so far to the runner, for targets that never return from `main()`
+/// or call `exit()` (or that link the static library and want to choose when to dump).
+#[no_mangle]
+pub extern "C" fn quikcov_dump() {
+    if !ipc::enabled() {
+        return
+    }
+
+    if gcov_info::enabled() {
+        gcov_info::capture();
+    } else {
+        gcov_info::libgcov_dump();
+    }
+}
+
+// Runs once the process's destructors (including those dumping coverage) have run
+#[used]
+#[cfg_attr(any(target_os = "linux", target_os = "android"), link_section = ".fini_array")]
+#[cfg_attr(any(target_os = "macos", target_os = "ios"), link_section = "__DATA,__mod_term_func")]
+static QUIKCOV_FINI: extern "C" fn() = quikcov_fini;
+
+extern "C" fn quikcov_fini() {
+    if ipc::enabled() {
+        ipc::send(Body::DumpComplete { seed: None });
+    }
+}
@@ -0,0 +1,105 @@
+//! Persistent mode: drives a libFuzzer-style `LLVMFuzzerTestOneInput()` in a loop instead of
+//! running the target's `main()`, dumping and resetting the gcov counters after every input.
+//!
+//! Enabled by `QUIKCOV_PERSISTENT_FD`, which names the pipe the runner sends inputs through. Each
+//! request is a `u64` seed id and a `u32` length (both big-endian) followed by the input itself.
+//! After running an input, the resulting `.gcda` files are sent as usual and followed by a
+//! `DumpComplete` message tagged with the seed id.
+//!
+//! The target still needs some `main()` to link (a standalone libFuzzer driver is fine, as it is
+//! never called), and must export `LLVMFuzzerTestOneInput`, `__gcov_dump` and `__gcov_reset` in
+//! its dynamic symbol table. Since libgcov is a static archive, the latter two have to be pulled
+//! in explicitly: link with `-rdynamic -Wl,--undefined=__gcov_dump,--undefined=__gcov_reset`.
+//! They aren't needed when capturing coverage directly from `__gcov_master` (see
+//! [`crate::gcov_info`]).
+
+use std::sync::OnceLock;
+
+use quikcov_common::protocol::{self, Body};
+
+use crate::forkserver::MainFn;
+use crate::symbol::lookup;
+use crate::{gcov_info, ipc};
+
+type TestOneInputFn = unsafe extern "C" fn(*const u8, libc::size_t) -> libc::c_int;
+type InitializeFn = unsafe extern "C" fn(*mut libc::c_int, *mut *mut *mut libc::c_char) -> libc::c_int;
+type GcovFn = unsafe extern "C" fn();
+
+static CONTROL_FD: OnceLock<Option<libc::c_int>> = OnceLock::new();
+
+fn control_fd() -> Option<libc::c_int> {
+    *CONTROL_FD.get_or_init(|| {
+        let control_fd = std::env::var(protocol::PERSISTENT_FD_ENV).ok()?.parse().ok()?;
+        ipc::enabled().then_some(control_fd)
+    })
+}
+
+/// Called from `__libc_start_main()`, returning the `main()` that should be run in its place.
+pub fn on_start_main(main: MainFn) -> MainFn {
+    let control_fd = control_fd();
+
+    // Programs executed by the target must not try to run in persistent mode themselves. As with
+    // the forkserver's variables, this is done before `main()` while no other thread can race it.
+    std::env::remove_var(protocol::PERSISTENT_FD_ENV);
+
+    match control_fd {
+        Some(_) => persistent_main,
+        None => main,
+    }
+}
+
+unsafe extern "C" fn gcov_info_dump() {
+    gcov_info::capture();
+}
+
+unsafe extern "C" fn gcov_info_reset() {
+    gcov_info::reset();
+}
+
+unsafe extern "C" fn persistent_main(mut argc: libc::c_int, mut argv: *mut *mut libc::c_char, _envp: *mut *mut libc::c_char) -> libc::c_int {
+    let Some(control_fd) = control_fd() else {
+        libc::_exit(1);
+    };
+
+    let Some(test_one_input) = lookup::<TestOneInputFn>(c"LLVMFuzzerTestOneInput") else {
+        ipc::send(Body::Error("persistent mode requires LLVMFuzzerTestOneInput to be exported by the target".to_string()));
+        libc::_exit(1);
+    };
+
+    // Direct capture reads and clears the counters itself
+    let (gcov_dump, gcov_reset): (GcovFn, GcovFn) = if gcov_info::enabled() {
+        (gcov_info_dump, gcov_info_reset)
+    } else if let (Some(gcov_dump), Some(gcov_reset)) = (lookup::<GcovFn>(c"__gcov_dump"), lookup::<GcovFn>(c"__gcov_reset")) {
+        (gcov_dump, gcov_reset)
+    } else {
+        ipc::send(Body::Error("persistent mode requires __gcov_dump and __gcov_reset to be exported by the target".to_string()));
+        libc::_exit(1);
+    };
+
+    if let Some(initialize) = lookup::<InitializeFn>(c"LLVMFuzzerInitialize") {
+        initialize(&mut argc, &mut argv);
+    }
+
+    let mut input = Vec::new();
+    loop {
+        let mut header = [0u8; 12];
+        if !ipc::read_exact(control_fd, &mut header) {
+            // Counters were reset after the last dump, so there's nothing left worth dumping
+            libc::_exit(0);
+        }
+
+        let seed = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+
+        input.resize(len, 0);
+        if !ipc::read_exact(control_fd, &mut input) {
+            libc::_exit(0);
+        }
+
+        test_one_input(input.as_ptr(), input.len());
+
+        gcov_dump();
+        gcov_reset();
+        ipc::send(Body::DumpComplete { seed: Some(seed) });
+    }
+}
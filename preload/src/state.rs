@@ -1,35 +1,131 @@
-use std::sync::{OnceLock, Mutex};
+use std::sync::{Once, OnceLock, Mutex, MutexGuard, PoisonError};
 use std::os::fd::RawFd;
+use std::cell::RefCell;
 use std::collections::HashMap;
 
 use fxhash::FxBuildHasher;
-use serde::{Deserialize, Serialize};
+use quikcov_common::protocol::PIPE_FD_ENV;
+use quikcov_common::shm::{ShmRegion, SHM_FD_ENV};
 
-use crate::QUIKCOV_PIPE_ENV;
+#[cfg(target_os = "linux")]
+use crate::desock::Sockets;
+use crate::stream::GcdaStream;
 
-static IPC_WRITER: OnceLock<Mutex<RawFd>> = OnceLock::new();
-static GCDA_FILES: OnceLock<Mutex<HashMap<libc::c_int, Gcda, FxBuildHasher>>> = OnceLock::new();
+static IPC_WRITER: OnceLock<Option<Mutex<RawFd>>> = OnceLock::new();
+static GCDA_FILES: OnceLock<Mutex<HashMap<libc::c_int, GcdaStream, FxBuildHasher>>> = OnceLock::new();
 static FD_MAP: OnceLock<Mutex<HashMap<usize, libc::c_int, FxBuildHasher>>> = OnceLock::new();
+#[cfg(target_os = "linux")]
+static DESOCKED: OnceLock<Mutex<Sockets>> = OnceLock::new();
+static SHM_REGION: OnceLock<Option<ShmRegion>> = OnceLock::new();
+static ATFORK: Once = Once::new();
 
+struct ForkGuards {
+    fd_map: MutexGuard<'static, HashMap<usize, libc::c_int, FxBuildHasher>>,
+    gcda_files: MutexGuard<'static, HashMap<libc::c_int, GcdaStream, FxBuildHasher>>,
+    // Desocketed descriptors stay valid in the child, so this is only locked, never cleared
+    #[cfg(target_os = "linux")]
+    _desocked: MutexGuard<'static, Sockets>,
+    _ipc_writer: Option<MutexGuard<'static, RawFd>>,
+}
 
-#[derive(Deserialize, Serialize)]
-pub struct Gcda {
-    pub filepath: String,
-    pub data: Vec<u8>,
+thread_local! {
+    // Locks held by the forking thread between the `prepare` and `parent`/`child` atfork handlers
+    static FORK_GUARDS: RefCell<Option<ForkGuards>> = const { RefCell::new(None) };
 }
 
-pub fn ipc_writer() -> &'static Mutex<RawFd> {
+/// Returns the pipe to the runner, if the environment names one.
+pub fn ipc_writer() -> Option<&'static Mutex<RawFd>> {
+    register_atfork();
     IPC_WRITER.get_or_init(|| {
-        let pipe_str = std::env::vars().find(|(key, _)| key == QUIKCOV_PIPE_ENV).expect("missing QUIKCOV_PIPE_ENV environment variable").1;
-        let pipe_fd: i32 = pipe_str.parse().expect("QUIKCOV_PIPE_ENV must contain a positive integer indicating a pipe file descriptor");
-        Mutex::new(RawFd::from(pipe_fd))
-    })
+        let pipe_fd: RawFd = std::env::var(PIPE_FD_ENV).ok()?.parse().ok()?;
+        Some(Mutex::new(pipe_fd))
+    }).as_ref()
 }
 
-pub fn gcda_files() -> &'static Mutex<HashMap<libc::c_int, Gcda, FxBuildHasher>> {
+pub fn gcda_files() -> &'static Mutex<HashMap<libc::c_int, GcdaStream, FxBuildHasher>> {
+    register_atfork();
     GCDA_FILES.get_or_init(|| Mutex::new(HashMap::with_hasher(FxBuildHasher::default())))
 }
 
 pub fn fd_map() -> &'static Mutex<HashMap<usize, libc::c_int, FxBuildHasher>> {
+    register_atfork();
     FD_MAP.get_or_init(|| Mutex::new(HashMap::with_hasher(FxBuildHasher::default())))
 }
+
+/// Returns the sockets replaced by fake ones, keyed by their descriptor.
+#[cfg(target_os = "linux")]
+pub fn desocked() -> &'static Mutex<Sockets> {
+    register_atfork();
+    DESOCKED.get_or_init(|| Mutex::new(HashMap::with_hasher(FxBuildHasher::default())))
+}
+
+/// Returns the shared-memory region passed in by the runner, if any.
+///
+/// The mapping is `MAP_SHARED`, so forked children keep writing to the same region as their parent.
+pub fn shm_region() -> Option<&'static ShmRegion> {
+    SHM_REGION.get_or_init(|| {
+        let shm_fd: libc::c_int = std::env::var(SHM_FD_ENV).ok()?.parse().ok()?;
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(shm_fd, &mut stat) } != 0 || stat.st_size <= 0 {
+            return None
+        }
+
+        let len = stat.st_size as usize;
+        let base = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, shm_fd, 0) };
+        if base == libc::MAP_FAILED {
+            return None
+        }
+
+        let region = unsafe { ShmRegion::attach(base as *mut u8, len) };
+        if region.is_none() {
+            unsafe { libc::munmap(base, len) };
+        }
+        region
+    }).as_ref()
+}
+
+/// Locks `mutex` even if some thread panicked while holding it; the target must never be brought
+/// down over our own bookkeeping.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the (pid, ppid) pair of the calling process.
+pub fn process_ids() -> (u32, u32) {
+    unsafe { (libc::getpid() as u32, libc::getppid() as u32) }
+}
+
+fn register_atfork() {
+    ATFORK.call_once(|| unsafe {
+        libc::pthread_atfork(Some(atfork_prepare), Some(atfork_parent), Some(atfork_child));
+    });
+}
+
+// Every piece of state is locked across `fork()` so that the child never inherits a lock held
+// by some other (now nonexistent) thread, nor a half-updated map.
+extern "C" fn atfork_prepare() {
+    let fork_guards = ForkGuards {
+        fd_map: lock(fd_map()),
+        gcda_files: lock(gcda_files()),
+        #[cfg(target_os = "linux")]
+        _desocked: lock(desocked()),
+        _ipc_writer: IPC_WRITER.get().and_then(Option::as_ref).map(lock),
+    };
+    FORK_GUARDS.with(|guards| *guards.borrow_mut() = Some(fork_guards));
+}
+
+extern "C" fn atfork_parent() {
+    FORK_GUARDS.with(|guards| drop(guards.borrow_mut().take()));
+}
+
+// Any `.gcda` file that was open at the time of the fork belongs to the parent; the child must
+// neither ship its partial contents nor mistake the parent's `FILE` handles for its own.
+extern "C" fn atfork_child() {
+    FORK_GUARDS.with(|guards| {
+        if let Some(mut fork_guards) = guards.borrow_mut().take() {
+            fork_guards.fd_map.clear();
+            fork_guards.gcda_files.clear();
+        }
+    });
+}
@@ -0,0 +1,136 @@
+//! Streams `.gcda` files to the runner while libgcov writes them, so that no more than
+//! [`GCDA_CHUNK_LEN`] bytes of any file are held in memory at once.
+//!
+//! Files that never outgrow a single chunk are still sent whole when closed.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use quikcov_common::protocol::{Body, Gcda, GCDA_CHUNK_LEN};
+
+use crate::{fallback, ipc, state};
+
+static NEXT_FILE: AtomicU32 = AtomicU32::new(0);
+
+/// Where the contents of a file currently go.
+enum Sink {
+    Runner,
+    /// The runner couldn't be reached before any of the file was sent; the rest of it goes to the
+    /// descriptor opened by the fallback
+    Fallback(libc::c_int),
+    /// The file is incomplete on every side and its remainder is discarded
+    Lost,
+}
+
+/// A `.gcda` file being written by libgcov.
+pub struct GcdaStream {
+    filepath: String,
+    file: u32,
+    chunks: u32,
+    buf: Vec<u8>,
+    sink: Sink,
+}
+
+impl GcdaStream {
+    pub fn new(filepath: String) -> Self {
+        Self {
+            filepath,
+            file: NEXT_FILE.fetch_add(1, Ordering::Relaxed),
+            chunks: 0,
+            buf: Vec::new(),
+            sink: Sink::Runner,
+        }
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) {
+        match self.sink {
+            Sink::Runner => (),
+            Sink::Fallback(fd) => return self.write_fallback(fd, data),
+            Sink::Lost => return,
+        }
+
+        while self.buf.len() + data.len() >= GCDA_CHUNK_LEN {
+            let (head, tail) = data.split_at(GCDA_CHUNK_LEN - self.buf.len());
+            self.buf.extend_from_slice(head);
+            data = tail;
+
+            self.flush_chunk();
+            if !matches!(self.sink, Sink::Runner) {
+                return self.write(data)
+            }
+        }
+
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Completes the file once libgcov closes it.
+    pub fn finish(mut self) {
+        match self.sink {
+            Sink::Runner if self.chunks == 0 => {
+                // Small enough to have never left the buffer, so it can go as a whole
+                if !self.buf.is_empty() {
+                    ipc::send_gcda(Gcda {
+                        filepath: self.filepath,
+                        data: self.buf,
+                    });
+                }
+            }
+            Sink::Runner => {
+                if !self.buf.is_empty() {
+                    self.flush_chunk();
+                }
+                if matches!(self.sink, Sink::Runner) && !ipc::send(Body::GcdaEnd { file: self.file, chunks: self.chunks }) {
+                    fallback::count_dropped();
+                }
+            }
+            Sink::Fallback(fd) => unsafe {
+                libc::close(fd);
+            }
+            Sink::Lost => (),
+        }
+    }
+
+    fn flush_chunk(&mut self) {
+        if self.chunks == 0 && !ipc::send(Body::GcdaBegin { file: self.file, filepath: self.filepath.clone() }) {
+            // Nothing reached the runner yet, so the whole file can still be handed to the fallback
+            let buf = std::mem::take(&mut self.buf);
+            self.sink = match fallback::create(&self.filepath) {
+                Some(fd) => Sink::Fallback(fd),
+                None => Sink::Lost,
+            };
+            if let Sink::Fallback(fd) = self.sink {
+                self.write_fallback(fd, &buf);
+            }
+            return
+        }
+
+        let sent = match state::shm_region().and_then(|region| region.write(&self.buf)) {
+            Some(offset) => ipc::send(Body::GcdaChunkShm {
+                file: self.file,
+                seq: self.chunks,
+                offset,
+                len: self.buf.len() as u64,
+            }),
+            None => ipc::send(Body::GcdaChunk {
+                file: self.file,
+                seq: self.chunks,
+                data: std::mem::replace(&mut self.buf, Vec::with_capacity(GCDA_CHUNK_LEN)),
+            }),
+        };
+        self.buf.clear();
+        self.chunks += 1;
+
+        if !sent {
+            // Part of the file already went to the runner, which will discard it as incomplete
+            fallback::count_dropped();
+            self.sink = Sink::Lost;
+        }
+    }
+
+    fn write_fallback(&mut self, fd: libc::c_int, data: &[u8]) {
+        if ipc::write_all(fd, data).is_err() {
+            fallback::count_dropped();
+            unsafe { libc::close(fd) };
+            self.sink = Sink::Lost;
+        }
+    }
+}
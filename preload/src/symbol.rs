@@ -0,0 +1,14 @@
+//! Looks up symbols the target may or may not export, such as libgcov's entry points or a fuzzing
+//! harness, without requiring them at link time.
+
+use std::ffi::CStr;
+
+/// Finds `symbol` among everything loaded into the process, as a `T` (usually a function pointer).
+///
+/// # Safety
+///
+/// `T` must be pointer-sized and match what the symbol actually is.
+pub unsafe fn lookup<T>(symbol: &CStr) -> Option<T> {
+    let ptr = libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr());
+    (!ptr.is_null()).then(|| std::mem::transmute_copy(&ptr))
+}
@@ -0,0 +1,53 @@
+//! Builds the preload library for the same target and profile as quikcov, so that it can be
+//! embedded in the binary (see `src/preload.rs`).
+//!
+//! The preload is built from its sources in the workspace (`../preload`), so quikcov only builds
+//! from within the workspace: a package made by `cargo package` lacks them, which is why it isn't
+//! published.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The file name of the preload `cdylib`
+const LIBRARY: &str = "libquikcov_preload.so";
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let target = env::var("TARGET").unwrap();
+    let profile = env::var("PROFILE").unwrap();
+
+    let preload_dir = manifest_dir.join("../preload");
+    let common_dir = manifest_dir.join("../common");
+    for path in [preload_dir.join("Cargo.toml"), preload_dir.join("src"), common_dir.join("Cargo.toml"), common_dir.join("src")] {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+
+    // A target directory of its own, as the one we're being built in is locked for the duration
+    let target_dir = out_dir.join("preload");
+    let mut cargo = Command::new(env::var_os("CARGO").unwrap());
+    cargo.arg("build")
+        .arg("--lib")
+        .arg("--manifest-path").arg(preload_dir.join("Cargo.toml"))
+        .arg("--target").arg(&target)
+        .arg("--target-dir").arg(&target_dir)
+        // Build scripts aren't told whether cargo was run with `--offline`, `--locked` or
+        // `--frozen`, but there's no need to know: everything the preload depends on is a
+        // dependency of ours too, locked in the same workspace lockfile, so it has all been fetched
+        // by now and the lockfile is up to date
+        .arg("--offline")
+        .arg("--locked");
+    if profile == "release" {
+        cargo.arg("--release");
+    }
+
+    let status = cargo.status().expect("failed to run cargo to build the preload library");
+    if !status.success() {
+        panic!("failed to build the preload library ({})", status);
+    }
+
+    let library = target_dir.join(&target).join(&profile).join(LIBRARY);
+    fs::copy(&library, out_dir.join(LIBRARY)).unwrap_or_else(|e| panic!("failed to copy {}: {}", library.display(), e));
+}
@@ -0,0 +1,227 @@
+//! Reads `quikcov.toml`, which declares how each target is run and where its coverage is so that
+//! the invocation for it can be checked in next to it, e.g.:
+//!
+//! ```toml
+//! [target.server]
+//! command = ["build/server", "--config", "@@"]
+//! seed-queue = "queue"
+//! output = "results/server"
+//! cov-path = ["server=build", "libcodec=build/lib"]
+//! exclude = ["third_party/**"]
+//! path-map = ["/ci/build=build"]
+//! input-mode = "file"
+//! timeout = 2000
+//!
+//! [target.server.env]
+//! ASAN_OPTIONS = "detect_leaks=0"
+//! ```
+//!
+//! Relative paths are taken from the directory the file is in. Flags given on the command line
+//! take precedence over the file (`--no-abs-path` turning off an `abs-path` set in it).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::coverage::{CoverageArgs, PathMap};
+use crate::executor::InputMode;
+use crate::walk::CoverageRoot;
+
+/// The targets declared in a configuration file, keyed by name
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    target: BTreeMap<String, TargetConfig>,
+}
+
+/// How a target is run, and where its coverage is. Each key stands in for the flag of the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TargetConfig {
+    /// The command that runs the target, `@@` standing for the path of the seed
+    pub command: Vec<String>,
+    pub preload_path: Option<PathBuf>,
+    pub seed_queue: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub cov_path: Vec<CoverageRoot>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub abs_path: bool,
+    pub profile_dir: Option<String>,
+    pub path_map: Vec<PathMap>,
+    pub input_mode: Option<InputMode>,
+    /// In milliseconds
+    pub timeout: Option<u64>,
+    /// Variables set in the target's environment
+    pub env: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Reads the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::parse(&text, path)
+    }
+
+    /// Parses `text`, the contents of the configuration file at `path`.
+    fn parse(text: &str, path: &Path) -> Result<Self, String> {
+        let mut config: Config = toml::from_str(text).map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for (name, target) in &mut config.target {
+            if target.timeout == Some(0) {
+                return Err(format!("failed to parse {}: the timeout of target `{}` must be at least 1ms", path.display(), name))
+            }
+            target.resolve_paths(base);
+        }
+        Ok(config)
+    }
+
+    /// Returns the configuration of the target named `name`, which may be left out if the file
+    /// only declares one.
+    pub fn target(mut self, name: Option<&str>) -> Result<TargetConfig, String> {
+        let names = || self.target.keys().map(String::as_str).collect::<Vec<_>>().join(", ");
+        match name {
+            Some(name) => {
+                let known = names();
+                self.target.remove(name).ok_or_else(|| format!("no target `{}` in the configuration (declared: {})", name, known))
+            }
+            None if self.target.len() == 1 => Ok(self.target.into_values().next().unwrap()),
+            None if self.target.is_empty() => Err("the configuration declares no targets".to_string()),
+            None => Err(format!("the configuration declares several targets, pick one with `--target` ({})", names())),
+        }
+    }
+}
+
+impl TargetConfig {
+    /// Makes the relative paths of the files quikcov reads and writes relative to `base` instead.
+    /// The target's command is left as it is, since it may well be looked up in `PATH`.
+    fn resolve_paths(&mut self, base: &Path) {
+        for path in [&mut self.preload_path, &mut self.seed_queue, &mut self.output].into_iter().flatten() {
+            *path = base.join(&*path);
+        }
+        for root in &mut self.cov_path {
+            root.path = base.join(&root.path);
+        }
+        for map in &mut self.path_map {
+            map.local = base.join(&map.local);
+        }
+    }
+
+    /// Fills in what wasn't given in `args` on the command line.
+    pub fn apply_coverage(&self, args: &mut CoverageArgs) {
+        if args.cov_path.is_empty() {
+            args.cov_path = self.cov_path.clone();
+        }
+        if args.include.is_empty() {
+            args.include = self.include.clone();
+        }
+        if args.exclude.is_empty() {
+            args.exclude = self.exclude.clone();
+        }
+        if args.path_map.is_empty() {
+            args.path_map = self.path_map.clone();
+        }
+        if !args.abs_path && !args.no_abs_path {
+            args.abs_path = self.abs_path;
+        }
+        if args.profile_dir.is_none() {
+            args.profile_dir = self.profile_dir.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    const PATH: &str = "/srv/fuzz/quikcov.toml";
+
+    fn parse(text: &str) -> Result<Config, String> {
+        Config::parse(text, Path::new(PATH))
+    }
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        coverage: CoverageArgs,
+    }
+
+    fn coverage_args(args: &[&str]) -> CoverageArgs {
+        Cli::parse_from(std::iter::once("quikcov").chain(args.iter().copied())).coverage
+    }
+
+    #[test]
+    fn picks_targets() {
+        let config = || parse("[target.server]\ncommand = [\"server\"]\n[target.client]\ncommand = [\"client\"]\n").unwrap();
+        assert_eq!(config().target(Some("client")).unwrap().command, ["client"]);
+        assert!(config().target(Some("proxy")).unwrap_err().contains("client, server"));
+        assert!(config().target(None).is_err());
+
+        let config = parse("[target.server]\ncommand = [\"server\"]\n").unwrap();
+        assert_eq!(config.target(None).unwrap().command, ["server"]);
+        assert!(parse("").unwrap().target(None).is_err());
+    }
+
+    #[test]
+    fn rebases_relative_paths() {
+        let target = parse(r#"
+            [target.server]
+            command = ["build/server", "@@"]
+            seed-queue = "queue"
+            output = "/tmp/results"
+            cov-path = ["lib=build/lib", "build"]
+            path-map = ["/ci/build=build"]
+        "#).unwrap().target(None).unwrap();
+
+        assert_eq!(target.command, ["build/server", "@@"]);
+        assert_eq!(target.seed_queue, Some(PathBuf::from("/srv/fuzz/queue")));
+        assert_eq!(target.output, Some(PathBuf::from("/tmp/results")));
+        assert_eq!(target.cov_path, [
+            CoverageRoot { component: "lib".to_string(), path: PathBuf::from("/srv/fuzz/build/lib") },
+            CoverageRoot { component: "build".to_string(), path: PathBuf::from("/srv/fuzz/build") },
+        ]);
+        assert_eq!(target.path_map, [PathMap { build: PathBuf::from("/ci/build"), local: PathBuf::from("/srv/fuzz/build") }]);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(parse("[target.server]\ncomand = [\"server\"]\n").is_err());
+        assert!(parse("[target.server]\ntimeout = 0\n").is_err());
+        assert!(parse("[target.server]\ninput-mode = \"socket\"\n").is_err());
+        assert!(parse("[target.server]\ncov-path = [\"lib=\"]\n").is_err());
+        assert!(parse("[targets.server]\n").is_err());
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let target = parse(r#"
+            [target.server]
+            cov-path = ["build"]
+            exclude = ["third_party/**"]
+            abs-path = true
+            profile-dir = "/tmp/profiles"
+        "#).unwrap().target(None).unwrap();
+
+        let mut args = coverage_args(&["--exclude", "vendor/**"]);
+        target.apply_coverage(&mut args);
+        assert_eq!(args.cov_path, target.cov_path);
+        assert_eq!(args.exclude, ["vendor/**"]);
+        assert!(args.abs_path);
+        assert_eq!(args.profile_dir.as_deref(), Some("/tmp/profiles"));
+
+        let mut args = coverage_args(&["--no-abs-path"]);
+        target.apply_coverage(&mut args);
+        assert!(!args.abs_path);
+
+        // The last of the two flags wins
+        let mut args = coverage_args(&["--no-abs-path", "--abs-path"]);
+        TargetConfig::default().apply_coverage(&mut args);
+        assert!(args.abs_path);
+    }
+}
@@ -0,0 +1,261 @@
+//! Loads the `.gcno` files of the program, and builds its coverage from the `.gcda` files matched
+//! to them.
+
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::builder::{OsStringValueParser, TypedValueParser};
+use fxhash::FxBuildHasher;
+use quikcov_common::gcda_path::{self, Relocation};
+use quikcov_common::prelude::*;
+use serde::Deserialize;
+
+use crate::matcher::{Builders, GcdaMatcher};
+use crate::results::ResultSet;
+use crate::walk::{self, CoverageRoot, Filter};
+
+/// Where the program's `.gcno` files are, and how its `.gcda` files are named.
+#[derive(clap::Args, Debug)]
+pub struct CoverageArgs {
+    /// A directory containing .gcno and .gcda files for the program, optionally prefixed with the
+    /// name of the component (e.g. a library it loads) they belong to (may be repeated)
+    #[arg(long, value_name = "[NAME=]PATH", value_parser = OsStringValueParser::new().try_map(CoverageRoot::try_from))]
+    pub cov_path: Vec<CoverageRoot>,
+    /// Only read the .gcno files whose path under their `--cov-path` matches this glob (may be repeated)
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+    /// Skip the .gcno files, and directories, whose path under their `--cov-path` matches this glob (may
+    /// be repeated)
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// Instructs quikcov to prepend any absolute path reported in .gcno/.gcda files to the function location
+    #[arg(short, long, overrides_with = "no_abs_path")]
+    pub abs_path: bool,
+    /// Don't prepend absolute paths, even if the configuration sets `abs-path`
+    #[arg(long, overrides_with = "abs_path")]
+    pub no_abs_path: bool,
+    /// The directory the program was compiled with `-fprofile-dir` set to, if any
+    #[arg(long, value_name = "PATH")]
+    pub profile_dir: Option<String>,
+    /// The program was built in BUILD, but its .gcno files have since been moved to LOCAL (may be
+    /// repeated)
+    #[arg(long, value_name = "BUILD=LOCAL", value_parser = OsStringValueParser::new().try_map(PathMap::try_from))]
+    pub path_map: Vec<PathMap>,
+}
+
+/// A directory the program was built in, and where its build tree is now: the program still writes
+/// its `.gcda` files below the former, while its `.gcno` files are read from the latter.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PathMap {
+    pub build: PathBuf,
+    pub local: PathBuf,
+}
+
+impl PathMap {
+    /// Returns what the program, built in `build`, calls the absolute local path `path`, if it's
+    /// below `local` (taken relative to `cwd`).
+    fn apply(&self, path: &Path, cwd: &Path) -> Option<PathBuf> {
+        let local: PathBuf = cwd.join(&self.local).components().collect();
+        path.strip_prefix(local).ok().map(|rest| self.build.join(rest))
+    }
+}
+
+impl TryFrom<OsString> for PathMap {
+    type Error = String;
+
+    fn try_from(s: OsString) -> Result<Self, Self::Error> {
+        let bytes = s.as_bytes();
+        match bytes.iter().position(|&byte| byte == b'=') {
+            Some(i) if i > 0 && i + 1 < bytes.len() => Ok(Self {
+                build: PathBuf::from(OsStr::from_bytes(&bytes[..i])),
+                local: PathBuf::from(OsStr::from_bytes(&bytes[i + 1..])),
+            }),
+            _ => Err(format!("invalid path mapping `{}` (expected `<build>=<local>`)", s.to_string_lossy())),
+        }
+    }
+}
+
+impl FromStr for PathMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OsString::from(s).try_into()
+    }
+}
+
+impl TryFrom<String> for PathMap {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The component each builder belongs to, keyed like the builders
+pub type Components = HashMap<String, String, FxBuildHasher>;
+
+/// A builder for every `.gcno` file of the program, keyed by the path its `.gcda` file is expected at.
+pub struct Coverage {
+    pub builders: Builders,
+    pub matcher: GcdaMatcher,
+    pub components: Components,
+}
+
+impl Coverage {
+    /// Reads the `.gcno` files under every coverage directory that pass `filter`, deleting any
+    /// `.gcda` files left there first if `clear_gcda` is set.
+    pub fn load(args: &CoverageArgs, filter: &Filter, clear_gcda: bool) -> Self {
+        let mut coverage = Coverage {
+            builders: Builders::default(),
+            matcher: GcdaMatcher::default(),
+            components: Components::default(),
+        };
+
+        // Targets inherit our environment, so they relocate their .gcda files the same way
+        let relocation = Relocation::from_env();
+        let cwd = std::env::current_dir().unwrap();
+        // Every .gcno file read so far, with the path of its builder and its depth below its root,
+        // since nested roots (e.g. `build` and `build/lib`) reach the same files
+        let mut loaded: HashMap<PathBuf, (String, usize)> = HashMap::new();
+
+        for root in &args.cov_path {
+            let cov_files = walk::coverage_files(&root.path, filter);
+
+            if clear_gcda {
+                for gcda_file in cov_files.gcda {
+                    if let Err(e) = fs::remove_file(&gcda_file) {
+                        log::warn!("failed to remove old .gcda file {}: {}", gcda_file.display(), e);
+                    }
+                }
+            }
+
+            for gcno_file in cov_files.gcno {
+                let canonical = fs::canonicalize(&gcno_file).unwrap_or_else(|_| cwd.join(&gcno_file));
+                let depth = gcno_file.strip_prefix(&root.path).map_or(usize::MAX, |path| path.components().count());
+                if let Some((gcda_file, loaded_depth)) = loaded.get_mut(&canonical) {
+                    // It belongs to the innermost root it's under
+                    let component = coverage.components.get_mut(gcda_file.as_str()).unwrap();
+                    if depth < *loaded_depth {
+                        *component = root.component.clone();
+                        *loaded_depth = depth;
+                    }
+                    log::warn!("{} is under several coverage directories--counting it once, for {}", gcno_file.display(), component);
+                    continue
+                }
+
+                log::debug!("reading .gcno file {:?}", gcno_file);
+
+                let gcno_bytes = match fs::read(&gcno_file) {
+                    Ok(gcno_bytes) => gcno_bytes,
+                    Err(e) => {
+                        log::error!("failed to read .gcno file {}: {}--skipping", gcno_file.display(), e);
+                        continue
+                    }
+                };
+                if gcno_bytes.is_empty() {
+                    continue
+                }
+
+                let gcno = match Gcno::from_slice(&gcno_bytes) {
+                    Ok(gcno) => gcno,
+                    Err(e) => {
+                        log::error!("failed to parse .gcno file {}: {:?}--skipping", gcno_file.display(), e);
+                        continue
+                    }
+                };
+
+                let mut object_base = gcno_file.with_extension("");
+                if args.abs_path {
+                    let Some(cwd_path) = gcno.cwd.clone() else {
+                        panic!("abs-path flag set but no cwd located in .gcno files");
+                    };
+                    object_base = Path::new(&cwd_path).join(object_base);
+                }
+                // GCC names .gcda files after the absolute path of their object
+                let mut object_base: PathBuf = cwd.join(object_base).components().collect();
+                if let Some(build_path) = args.path_map.iter().find_map(|map| map.apply(&object_base, &cwd)) {
+                    object_base = build_path;
+                }
+
+                // The preload reports the paths of .gcda files the same lossy way, so the two still
+                // agree where they aren't UTF-8 (and stamps decide the match wherever they can)
+                let gcda_files = gcda_path::compile_time_paths(&object_base.to_string_lossy(), args.profile_dir.as_deref())
+                    .into_iter()
+                    .map(|path| relocation.apply(&path));
+                let gcda_file = coverage.matcher.add(&gcno, gcda_files);
+
+                log::debug!("expecting .gcda file \"{}\" for {}", gcda_file, root.component);
+                coverage.components.insert(gcda_file.clone(), root.component.clone());
+                coverage.builders.insert(gcda_file.clone(), FileCovBuilder::new(gcno));
+                loaded.insert(canonical, (gcda_file, depth));
+            }
+        }
+
+        coverage
+    }
+}
+
+/// Builds the coverage of each component from `builders`, which are keyed like `components`.
+pub fn build(builders: &Builders, components: &Components) -> ResultSet {
+    if builders.is_empty() {
+        panic!("no .gcno files found");
+    }
+
+    let mut coverage: BTreeMap<String, ProgCoverage> = BTreeMap::new();
+    for (gcda_file, builder) in builders {
+        let file_coverage = builder.clone().build().unwrap();
+        match coverage.entry(components[gcda_file].clone()) {
+            btree_map::Entry::Occupied(mut component) => component.get_mut().merge(file_coverage).unwrap(),
+            btree_map::Entry::Vacant(component) => _ = component.insert(file_coverage),
+        }
+    }
+    ResultSet { components: coverage }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Notes GCC 12.2 produced for an object (see `common/tests/gcc12.rs`)
+    const GCNO: &[u8] = include_bytes!("../../common/tests/fixtures/gcc12/sample.gcno");
+
+    fn args(cov_path: &[CoverageRoot]) -> CoverageArgs {
+        CoverageArgs {
+            cov_path: cov_path.to_vec(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            abs_path: false,
+            no_abs_path: false,
+            profile_dir: None,
+            path_map: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reads_files_under_nested_roots_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let build = dir.path().join("build");
+        fs::create_dir_all(build.join("lib")).unwrap();
+        fs::write(build.join("main.gcno"), GCNO).unwrap();
+        fs::write(build.join("lib/codec.gcno"), GCNO).unwrap();
+
+        let server = CoverageRoot { component: "server".to_string(), path: build.clone() };
+        let codec = CoverageRoot { component: "codec".to_string(), path: build.join("lib") };
+
+        // Whichever root is walked first, files go to the innermost one they're under
+        for roots in [[server.clone(), codec.clone()], [codec, server]] {
+            let coverage = Coverage::load(&args(&roots), &Filter::default(), false);
+            let components: BTreeMap<_, _> = coverage.components.iter()
+                .map(|(gcda_file, component)| (Path::new(gcda_file).strip_prefix(&build).unwrap().to_path_buf(), component.as_str()))
+                .collect();
+
+            assert_eq!(components, BTreeMap::from([(PathBuf::from("lib/codec.gcda"), "codec"), (PathBuf::from("main.gcda"), "server")]));
+            assert_eq!(coverage.builders.len(), 2);
+        }
+    }
+}
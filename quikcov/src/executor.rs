@@ -0,0 +1,1166 @@
+//! Runs the target program on seeds and collects the messages its processes send back.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::FileExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use command_fds::{CommandFdExt, FdMapping};
+use os_pipe::{PipeReader, PipeWriter};
+use quikcov_common::protocol::{self, Body, FrameReader, Gcda, Message};
+use quikcov_common::shm::{self, ShmRegion};
+use serde::{Deserialize, Serialize};
+
+use crate::net::NetInput;
+use crate::seed::SeedFormat;
+use crate::signal::Signal;
+
+/// How the target program is launched under the preload.
+#[derive(Clone, Debug)]
+pub struct Target {
+    /// The command (and optionally arguments) that runs the target
+    pub command: Vec<String>,
+    /// The LD_PRELOAD library to load
+    pub preload_path: PathBuf,
+    /// Size in bytes of the shared-memory region to pass .gcda contents through, if any
+    pub shm_size: Option<usize>,
+    /// How the preload captures the target's coverage
+    pub capture: CaptureMode,
+    /// What the preload does with coverage it can't send us
+    pub fallback: protocol::Fallback,
+    /// How each seed is handed to the target
+    pub input_mode: InputMode,
+    /// How the messages of each seed are laid out
+    pub seed_format: SeedFormat,
+    /// How long to wait between the messages of a seed
+    pub message_delay: Duration,
+    /// The signal the target should exit (and dump its coverage) on, if it needs one
+    pub dump_signal: Option<Signal>,
+    /// The port (0 for any) whose sockets the preload fakes, serving the seed read from stdin as
+    /// their only client
+    pub desock: Option<u16>,
+    /// How long a seed may run before the target is sent its dump signal (or, without one, killed)
+    pub timeout: Option<Duration>,
+    /// The resource limits the target runs under
+    pub limits: ResourceLimits,
+    /// Whether to capture what the target writes to stderr
+    pub capture_stderr: bool,
+}
+
+/// Resource limits applied to the target, and inherited by whatever it spawns.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceLimits {
+    /// `RLIMIT_AS`, in bytes
+    pub address_space: Option<u64>,
+    /// `RLIMIT_CPU`, in seconds
+    pub cpu: Option<u64>,
+    /// `RLIMIT_CORE`, in bytes
+    pub core: Option<u64>,
+}
+
+impl ResourceLimits {
+    fn is_empty(&self) -> bool {
+        self.address_space.is_none() && self.cpu.is_none() && self.core.is_none()
+    }
+
+    /// Applies the limits to the calling process. Runs between `fork()` and `exec()`, so it
+    /// mustn't allocate.
+    fn apply(&self) -> io::Result<()> {
+        // Past the soft CPU limit, the target gets a `SIGXCPU` that tells why it died before the
+        // hard limit's `SIGKILL`
+        let limits = [
+            (libc::RLIMIT_AS, self.address_space.map(|limit| (limit, limit))),
+            (libc::RLIMIT_CPU, self.cpu.map(|limit| (limit, limit + 1))),
+            (libc::RLIMIT_CORE, self.core.map(|limit| (limit, limit))),
+        ];
+        for (resource, limit) in limits {
+            let Some((soft, hard)) = limit else {
+                continue
+            };
+            let rlimit = libc::rlimit {
+                rlim_cur: soft as libc::rlim_t,
+                rlim_max: hard as libc::rlim_t,
+            };
+            if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                return Err(io::Error::last_os_error())
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the run of a seed ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum Outcome {
+    /// The target exited successfully
+    Ok,
+    /// The target ran past its timeout
+    Timeout,
+    /// The target was killed by a signal
+    Crash { signal: i32 },
+    /// The target exited with a non-zero status
+    Exit { code: i32 },
+    /// The seed couldn't be run at all
+    Skipped,
+}
+
+impl Outcome {
+    fn from_wait_status(wait_status: libc::c_int) -> Self {
+        if libc::WIFSIGNALED(wait_status) {
+            Outcome::Crash { signal: libc::WTERMSIG(wait_status) }
+        } else {
+            match libc::WEXITSTATUS(wait_status) {
+                0 => Outcome::Ok,
+                code => Outcome::Exit { code },
+            }
+        }
+    }
+}
+
+impl From<ExitStatus> for Outcome {
+    fn from(status: ExitStatus) -> Self {
+        Outcome::from_wait_status(status.into_raw())
+    }
+}
+
+/// What became of the run of a seed, besides the coverage it produced.
+#[derive(Debug)]
+pub struct Run {
+    pub outcome: Outcome,
+    /// How long the target spent on the seed
+    pub runtime: Duration,
+    /// The peak resident set size, in KiB, of the processes that ran the seed, where it can be told
+    /// apart from that of other seeds
+    pub max_rss: Option<u64>,
+    /// What the target wrote to stderr while running the seed, if it was captured
+    pub stderr: Option<Vec<u8>>,
+}
+
+impl Run {
+    fn skipped() -> Self {
+        Run {
+            outcome: Outcome::Skipped,
+            runtime: Duration::ZERO,
+            max_rss: None,
+            stderr: None,
+        }
+    }
+}
+
+/// The argument of the target's command that is replaced with the path of the seed being run
+pub const INPUT_PLACEHOLDER: &str = "@@";
+
+/// Replaces every [`INPUT_PLACEHOLDER`] in `arg` with `input`, which needn't be valid UTF-8.
+fn substitute_input(arg: &str, input: &Path) -> OsString {
+    let mut substituted = OsString::new();
+    for (i, part) in arg.split(INPUT_PLACEHOLDER).enumerate() {
+        if i > 0 {
+            substituted.push(input);
+        }
+        substituted.push(part);
+    }
+    substituted
+}
+
+impl Target {
+    /// Returns the target's command with [`INPUT_PLACEHOLDER`] replaced by `input` when seeds are
+    /// delivered as files (appending `input` if the command has no placeholder).
+    fn command_line(&self, input: Option<&Path>) -> (OsString, Vec<OsString>) {
+        let mut command_line: Vec<OsString> = self.command.iter().map(OsString::from).collect();
+
+        if let (InputMode::File, Some(input)) = (self.input_mode, input) {
+            if self.command.iter().any(|arg| arg.contains(INPUT_PLACEHOLDER)) {
+                command_line = self.command.iter().map(|arg| substitute_input(arg, input)).collect();
+            } else {
+                command_line.push(input.into());
+            }
+        }
+
+        let program = command_line.remove(0);
+        (program, command_line)
+    }
+
+    /// The stdin of a target run on the seed at `input`.
+    fn stdin(&self, input: &Path) -> Stdio {
+        match self.input_mode {
+            InputMode::Stdin => Stdio::from(fs::File::open(input).unwrap()),
+            InputMode::File => Stdio::null(),
+        }
+    }
+
+    /// Splits `seed` into its messages, keeping only those sent to `port` if it's a capture.
+    /// Returns `None` (having logged why) if the seed is malformed.
+    fn messages<'a>(&self, seed: &'a [u8], port: Option<u16>) -> Option<Vec<&'a [u8]>> {
+        match self.seed_format.decode(seed, port) {
+            Ok(messages) => Some(messages),
+            Err(e) => {
+                log::error!("seed isn't in {:?} format ({})--skipping", self.seed_format, e);
+                None
+            }
+        }
+    }
+
+    /// Indicates whether seeds are split into messages before they are written to stdin.
+    fn decodes_stdin(&self) -> bool {
+        self.input_mode == InputMode::Stdin && self.seed_format != SeedFormat::Raw
+    }
+
+    /// Indicates whether the messages written to stdin are framed for the preload to desocket one
+    /// by one.
+    fn frames_stdin(&self) -> bool {
+        self.decodes_stdin() && self.desock.is_some()
+    }
+
+    /// Lays out the `messages` of a seed as they are written to stdin.
+    fn stdin_messages(&self, messages: &[&[u8]]) -> Vec<Vec<u8>> {
+        messages.iter().map(|message| {
+            if self.frames_stdin() {
+                let mut framed = Vec::with_capacity(4 + message.len());
+                framed.extend((message.len() as u32).to_be_bytes());
+                framed.extend_from_slice(message);
+                framed
+            } else {
+                message.to_vec()
+            }
+        }).collect()
+    }
+
+    /// Reads the seed at `input` and splits it into the messages written to stdin, for targets
+    /// that have the seed decoded. Captures only yield the packets sent to the desocketed port.
+    fn read_stdin_messages(&self, input: &Path) -> Option<Vec<Vec<u8>>> {
+        let seed_bytes = fs::read(input).unwrap();
+        let port = self.desock.filter(|&port| port != 0);
+        let messages = self.messages(&seed_bytes, port)?;
+        Some(self.stdin_messages(&messages))
+    }
+}
+
+/// Writes `messages` to a pipe one at a time, pausing `delay` between them, on a thread of its own;
+/// returns the end of the pipe to be used as the target's stdin.
+fn feed_stdin(messages: Vec<Vec<u8>>, delay: Duration) -> (Stdio, std::thread::JoinHandle<()>) {
+    let (reader, mut writer) = os_pipe::pipe().unwrap();
+
+    let thread = std::thread::spawn(move || {
+        for (i, message) in messages.iter().enumerate() {
+            if i > 0 {
+                std::thread::sleep(delay);
+            }
+            // The target is free to exit without reading all of its input
+            if writer.write_all(message).is_err() {
+                break
+            }
+        }
+    });
+
+    (Stdio::from(reader), thread)
+}
+
+/// How a seed is delivered to the target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputMode {
+    /// On its stdin
+    #[default]
+    Stdin,
+    /// As a path on its command line, in place of `@@`
+    File,
+}
+
+/// How the preload gets hold of the target's coverage data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CaptureMode {
+    /// Intercept the .gcda files libgcov writes
+    #[default]
+    FileIo,
+    /// Serialize the counters registered with libgcov directly, never touching the filesystem
+    GcovInfo,
+}
+
+impl CaptureMode {
+    fn as_env_str(self) -> &'static str {
+        match self {
+            CaptureMode::FileIo => protocol::CAPTURE_FILE_IO,
+            CaptureMode::GcovInfo => protocol::CAPTURE_GCOV_INFO,
+        }
+    }
+}
+
+/// The point at which a forkserver stops the target and starts forking off children.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ForkserverMode {
+    /// The first time the target reads from stdin
+    StdinRead,
+    /// Just before the target's `main()` is called
+    Main,
+}
+
+impl ForkserverMode {
+    fn as_env_str(self) -> &'static str {
+        match self {
+            ForkserverMode::StdinRead => protocol::FORKSERVER_STDIN_READ,
+            ForkserverMode::Main => protocol::FORKSERVER_MAIN,
+        }
+    }
+}
+
+pub trait Executor {
+    /// Runs the target on the seed at `seed`, passing every message its processes send to `on_message`.
+    ///
+    /// Transport details (handshakes, shared memory) are dealt with here, so `on_message` only ever
+    /// sees `Body::Gcda` messages rather than `Body::GcdaShm`.
+    fn run(&mut self, seed: &Path, on_message: &mut dyn FnMut(Message)) -> Run;
+}
+
+/// Spawns a fresh process for every seed.
+pub struct SpawnExecutor {
+    target: Target,
+    transport: Transport,
+}
+
+impl SpawnExecutor {
+    pub fn new(target: Target) -> Self {
+        Self {
+            transport: Transport::new(&target),
+            target,
+        }
+    }
+}
+
+impl Executor for SpawnExecutor {
+    fn run(&mut self, seed: &Path, on_message: &mut dyn FnMut(Message)) -> Run {
+        let (stdin, feeder) = if self.target.decodes_stdin() {
+            let Some(messages) = self.target.read_stdin_messages(seed) else {
+                return Run::skipped()
+            };
+            let (stdin, feeder) = feed_stdin(messages, self.target.message_delay);
+            (stdin, Some(feeder))
+        } else {
+            (self.target.stdin(seed), None)
+        };
+
+        let (parent_read_pipe, child_write_pipe) = os_pipe::pipe().unwrap();
+        let (mut command, fd_mappings) = self.transport.command(&self.target, Some(seed), &child_write_pipe);
+        if self.target.timeout.is_some() {
+            // So that whatever it spawns is stopped along with it
+            command.process_group(0);
+        }
+
+        let start = Instant::now();
+        let mut process = command
+            .fd_mappings(fd_mappings).unwrap()
+            .stdin(stdin)
+            .spawn().unwrap();
+        drop(child_write_pipe);
+        // Holds our copy of the stdin pipe, which would keep the feeder from ever noticing the
+        // target is gone
+        drop(command);
+        let watchdog = self.target.timeout.map(|timeout| Watchdog::new(-(process.id() as libc::pid_t), timeout, self.target.dump_signal));
+
+        let mut channel = Channel::new(parent_read_pipe);
+        while let Some(message) = channel.recv(self.transport.shm_region()) {
+            on_message(message);
+        }
+
+        // Make sure the old process has died before starting another
+        let (status, max_rss) = wait(&mut process);
+        let runtime = start.elapsed();
+        let timed_out = watchdog.is_some_and(Watchdog::stop);
+        if let Some(feeder) = feeder {
+            feeder.join().unwrap();
+        }
+
+        Run {
+            outcome: if timed_out { Outcome::Timeout } else { Outcome::from(status) },
+            runtime,
+            max_rss,
+            stderr: self.transport.take_stderr(),
+        }
+    }
+}
+
+/// Starts the target once and has the preload fork off a child for every seed.
+///
+/// Each seed is staged in the same file, which is overwritten with the contents of each seed. It is
+/// either the forkserver's stdin, which each child rewinds before continuing, or (for file input)
+/// named on the command line. Seeds that are split into messages are staged with all of their
+/// messages back to back.
+pub struct ForkserverExecutor {
+    target: Target,
+    mode: ForkserverMode,
+    transport: Transport,
+    input_path: PathBuf,
+    input: fs::File,
+    server: Option<Server>,
+}
+
+/// A long-running target process that is handed work through a control pipe.
+struct Server {
+    process: Child,
+    control: PipeWriter,
+    channel: Channel,
+}
+
+impl ForkserverExecutor {
+    /// Creates a forkserver executor that stages each seed in the file at `input_path`.
+    pub fn new(target: Target, mode: ForkserverMode, input_path: PathBuf) -> Self {
+        let input = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&input_path).unwrap();
+
+        Self {
+            transport: Transport::new(&target),
+            target,
+            mode,
+            input_path,
+            input,
+            server: None,
+        }
+    }
+
+    fn start(&self) -> Server {
+        let (parent_read_pipe, child_write_pipe) = os_pipe::pipe().unwrap();
+        let (control_read_pipe, control_write_pipe) = os_pipe::pipe().unwrap();
+        // Every child shares the command line, so each seed is staged at the same path
+        let (mut command, mut fd_mappings) = self.transport.command(&self.target, Some(&self.input_path), &child_write_pipe);
+
+        fd_mappings.push(FdMapping {
+            parent_fd: control_read_pipe.as_raw_fd(),
+            child_fd: control_read_pipe.as_raw_fd(),
+        });
+
+        if self.target.timeout.is_some() {
+            command.env(protocol::FORKSERVER_GROUPS_ENV, "1");
+        }
+
+        let process = command
+            .env(protocol::FORKSERVER_ENV, self.mode.as_env_str())
+            .env(protocol::FORKSERVER_FD_ENV, format!("{}", control_read_pipe.as_raw_fd()))
+            .fd_mappings(fd_mappings).unwrap()
+            .stdin(self.target.stdin(&self.input_path))
+            .spawn().unwrap();
+        drop(child_write_pipe);
+        drop(control_read_pipe);
+
+        log::info!("started forkserver (pid {})", process.id());
+
+        Server {
+            process,
+            control: control_write_pipe,
+            channel: Channel::new(parent_read_pipe),
+        }
+    }
+
+    /// Stages the seed at `seed` for the next child, returning `false` if it's malformed.
+    fn stage_input(&mut self, seed: &Path) -> bool {
+        // Messages are only split for desocketed targets, framed so that the preload can tell
+        // them apart in the staged file
+        let seed_bytes = if self.target.decodes_stdin() {
+            let Some(messages) = self.target.read_stdin_messages(seed) else {
+                return false
+            };
+            messages.concat()
+        } else {
+            fs::read(seed).unwrap()
+        };
+
+        self.input.set_len(0).unwrap();
+        self.input.rewind().unwrap();
+        self.input.write_all(&seed_bytes).unwrap();
+        true
+    }
+}
+
+impl Executor for ForkserverExecutor {
+    fn run(&mut self, seed: &Path, on_message: &mut dyn FnMut(Message)) -> Run {
+        if !self.stage_input(seed) {
+            return Run::skipped()
+        }
+        self.transport.reset();
+
+        let mut server = match self.server.take() {
+            Some(server) => server,
+            None => self.start(),
+        };
+
+        if let Err(e) = server.control.write_all(&[0u8; 4]) {
+            log::error!("failed to request a child from the forkserver ({})--restarting it for the next seed", e);
+            server.stop();
+            return Run::skipped()
+        }
+
+        let start = Instant::now();
+        let mut child = None;
+        // Until it spawns a child, the forkserver itself is watched, in case it never reaches its
+        // fork point (or hangs there)
+        let spawn_timeout = self.target.timeout.map_or(SPAWN_TIMEOUT, |timeout| timeout.max(SPAWN_TIMEOUT));
+        let mut watchdog = Some(Watchdog::new(server.process.id() as libc::pid_t, spawn_timeout, None));
+        let (outcome, max_rss) = loop {
+            let Some(message) = server.channel.recv(self.transport.shm_region()) else {
+                let outcome = if child.is_none() && watchdog.take().is_some_and(Watchdog::stop) {
+                    log::error!("forkserver didn't spawn a child within {:?}--restarting it for the next seed", spawn_timeout);
+                    Outcome::Timeout
+                } else {
+                    log::error!("forkserver exited unexpectedly--restarting it for the next seed");
+                    Outcome::Skipped
+                };
+                server.stop();
+                // Whatever it wrote on the way out is the best clue as to why
+                return Run { outcome, runtime: start.elapsed(), stderr: self.transport.take_stderr(), ..Run::skipped() }
+            };
+
+            match message.body {
+                Body::ForkserverSpawned { child: pid } if message.pid == server.process.id() => {
+                    log::debug!("forkserver spawned child {}", pid);
+                    child = Some(pid);
+                    // Children lead process groups of their own. Replacing the forkserver's
+                    // watchdog calls it off
+                    watchdog = self.target.timeout.map(|timeout| Watchdog::new(-(pid as libc::pid_t), timeout, self.target.dump_signal));
+                }
+                Body::ForkserverExited { child: pid, wait_status, max_rss } if message.pid == server.process.id() && child == Some(pid) => {
+                    log::debug!("forkserver child {} exited with wait status {:#x}", pid, wait_status);
+                    if watchdog.take().is_some_and(Watchdog::stop) {
+                        break (Outcome::Timeout, max_rss)
+                    }
+                    break (Outcome::from_wait_status(wait_status), max_rss)
+                }
+                _ => on_message(message),
+            }
+        };
+
+        self.server = Some(server);
+        Run {
+            outcome,
+            runtime: start.elapsed(),
+            max_rss: Some(max_rss),
+            stderr: self.transport.take_stderr(),
+        }
+    }
+}
+
+impl Drop for ForkserverExecutor {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.stop();
+        }
+    }
+}
+
+impl Server {
+    /// Stops the server, returning its exit status.
+    fn stop(mut self) -> Option<ExitStatus> {
+        // Servers exit as soon as they see their control pipe close
+        drop(self.control);
+        match self.process.wait() {
+            Ok(status) => Some(status),
+            Err(e) => {
+                log::warn!("failed to wait on target server: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// How long a server may take to start listening
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a forkserver may take to spawn a child (at least the seed's own timeout), from
+/// starting up to reaching its fork point
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a server may take to exit once sent its dump signal before it is killed
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts the target afresh for every seed as a server, sends it the seed over the network once
+/// it's listening, and then signals it (and any processes it forked) to dump its coverage and exit.
+pub struct NetworkExecutor {
+    target: Target,
+    transport: Transport,
+    input: NetInput,
+}
+
+impl NetworkExecutor {
+    pub fn new(target: Target, input: NetInput) -> Self {
+        Self {
+            transport: Transport::new(&target),
+            target,
+            input,
+        }
+    }
+
+    /// Waits until the server is listening, returning `false` if it exits or takes too long.
+    fn wait_listening(&self, process: &mut Child) -> bool {
+        let start = Instant::now();
+        while !self.input.address.is_listening() {
+            if let Ok(Some(status)) = process.try_wait() {
+                log::error!("server exited ({}) before listening on {}", status, self.input.address);
+                return false
+            }
+            if start.elapsed() > LISTEN_TIMEOUT {
+                log::error!("server didn't start listening on {} within {:?}", self.input.address, LISTEN_TIMEOUT);
+                return false
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        true
+    }
+}
+
+impl Executor for NetworkExecutor {
+    fn run(&mut self, seed: &Path, on_message: &mut dyn FnMut(Message)) -> Run {
+        let seed_bytes = fs::read(seed).unwrap();
+        let Some(messages) = self.target.messages(&seed_bytes, Some(self.input.address.port)) else {
+            return Run::skipped()
+        };
+        let Some(Signal(dump_signal)) = self.target.dump_signal else {
+            panic!("network targets need a dump signal");
+        };
+
+        // Whatever is there already would be sent the seed in place of the target
+        if self.input.address.is_listening() {
+            log::error!("{} is already in use--skipping seed", self.input.address);
+            return Run::skipped()
+        }
+
+        let (parent_read_pipe, child_write_pipe) = os_pipe::pipe().unwrap();
+        let (mut command, fd_mappings) = self.transport.command(&self.target, None, &child_write_pipe);
+
+        // In a process group of its own, so that any workers it forks are shut down along with it
+        let start = Instant::now();
+        let mut process = command
+            .fd_mappings(fd_mappings).unwrap()
+            .process_group(0)
+            .stdin(Stdio::null())
+            .spawn().unwrap();
+        drop(child_write_pipe);
+        let pgid = process.id() as libc::pid_t;
+
+        if self.wait_listening(&mut process) {
+            if let Err(e) = self.input.send(&messages, self.target.message_delay) {
+                log::error!("failed to send seed to {}: {}", self.input.address, e);
+            }
+        }
+
+        unsafe { libc::kill(-pgid, dump_signal) };
+        let watchdog = Watchdog::new(-pgid, SHUTDOWN_TIMEOUT, None);
+
+        let mut channel = Channel::new(parent_read_pipe);
+        while let Some(message) = channel.recv(self.transport.shm_region()) {
+            on_message(message);
+        }
+
+        let timed_out = watchdog.stop();
+        let (status, max_rss) = wait(&mut process);
+        let runtime = start.elapsed();
+
+        // Servers are expected to exit on their dump signal
+        let outcome = match Outcome::from(status) {
+            _ if timed_out => Outcome::Timeout,
+            Outcome::Exit { code } if code == 128 + dump_signal => Outcome::Ok,
+            Outcome::Crash { signal } if signal == dump_signal => Outcome::Ok,
+            outcome => outcome,
+        };
+
+        Run {
+            outcome,
+            runtime,
+            max_rss,
+            stderr: self.transport.take_stderr(),
+        }
+    }
+}
+
+/// Stops a process (or process group) that outlives its deadline, unless stopped in time: with
+/// `signal` first, if given, so that it still gets to dump its coverage, and then with `SIGKILL`.
+struct Watchdog {
+    cancel: mpsc::Sender<()>,
+    fired: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Watches `target`, a pid or a negated process group id as taken by `kill()`.
+    fn new(target: libc::pid_t, timeout: Duration, signal: Option<Signal>) -> Self {
+        let (cancel, cancelled) = mpsc::channel();
+        let fired = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::spawn({
+            let fired = fired.clone();
+            move || {
+                let expired = |wait| matches!(cancelled.recv_timeout(wait), Err(mpsc::RecvTimeoutError::Timeout));
+                let name = match target {
+                    pgid if pgid < 0 => format!("process group {}", -pgid),
+                    pid => format!("process {}", pid),
+                };
+
+                if !expired(timeout) {
+                    return
+                }
+                fired.store(true, Ordering::Relaxed);
+
+                if let Some(signal) = signal {
+                    log::warn!("{} timed out after {:?}--sending it {}", name, timeout, signal);
+                    unsafe { libc::kill(target, signal.0) };
+                    if !expired(SHUTDOWN_TIMEOUT) {
+                        return
+                    }
+                    log::warn!("{} didn't exit within {:?} of {}--killing it", name, SHUTDOWN_TIMEOUT, signal);
+                } else {
+                    log::warn!("{} didn't exit within {:?}--killing it", name, timeout);
+                }
+                unsafe { libc::kill(target, libc::SIGKILL) };
+            }
+        });
+
+        Self {
+            cancel,
+            fired,
+            thread: Some(thread),
+        }
+    }
+
+    /// Calls off the watchdog, returning whether it had to step in.
+    fn stop(mut self) -> bool {
+        self.cancel();
+        self.fired.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&mut self) {
+        let _ = self.cancel.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Starts the target once and feeds every seed to its `LLVMFuzzerTestOneInput()` in-process.
+pub struct PersistentExecutor {
+    target: Target,
+    transport: Transport,
+    next_seed_id: u64,
+    server: Option<Server>,
+}
+
+impl PersistentExecutor {
+    pub fn new(target: Target) -> Self {
+        Self {
+            transport: Transport::new(&target),
+            target,
+            next_seed_id: 0,
+            server: None,
+        }
+    }
+
+    fn start(&self) -> Server {
+        let (parent_read_pipe, child_write_pipe) = os_pipe::pipe().unwrap();
+        let (control_read_pipe, control_write_pipe) = os_pipe::pipe().unwrap();
+        let (mut command, mut fd_mappings) = self.transport.command(&self.target, None, &child_write_pipe);
+
+        fd_mappings.push(FdMapping {
+            parent_fd: control_read_pipe.as_raw_fd(),
+            child_fd: control_read_pipe.as_raw_fd(),
+        });
+
+        if self.target.timeout.is_some() {
+            command.process_group(0);
+        }
+
+        let process = command
+            .env(protocol::PERSISTENT_FD_ENV, format!("{}", control_read_pipe.as_raw_fd()))
+            .fd_mappings(fd_mappings).unwrap()
+            .stdin(Stdio::null())
+            .spawn().unwrap();
+        drop(child_write_pipe);
+        drop(control_read_pipe);
+
+        log::info!("started persistent target (pid {})", process.id());
+
+        Server {
+            process,
+            control: control_write_pipe,
+            channel: Channel::new(parent_read_pipe),
+        }
+    }
+}
+
+impl Executor for PersistentExecutor {
+    fn run(&mut self, seed: &Path, on_message: &mut dyn FnMut(Message)) -> Run {
+        let seed_bytes = fs::read(seed).unwrap();
+        let Ok(seed_len) = u32::try_from(seed_bytes.len()) else {
+            log::error!("seed {} is too large for persistent mode--skipping", seed.display());
+            return Run::skipped()
+        };
+
+        let seed_id = self.next_seed_id;
+        self.next_seed_id += 1;
+        self.transport.reset();
+
+        let mut server = match self.server.take() {
+            Some(server) => server,
+            None => self.start(),
+        };
+
+        let mut request = Vec::with_capacity(12 + seed_bytes.len());
+        request.extend(seed_id.to_be_bytes());
+        request.extend(seed_len.to_be_bytes());
+        request.extend(seed_bytes);
+
+        if let Err(e) = server.control.write_all(&request) {
+            log::error!("failed to send seed to the persistent target ({})--restarting it for the next seed", e);
+            server.stop();
+            return Run::skipped()
+        }
+        let start = Instant::now();
+
+        // The whole target goes down on a timeout, to be restarted for the next seed
+        let watchdog = self.target.timeout.map(|timeout| Watchdog::new(-(server.process.id() as libc::pid_t), timeout, self.target.dump_signal));
+
+        // Every seed shares the one process, so its peak RSS says nothing about any of them
+        let outcome = loop {
+            let Some(message) = server.channel.recv(self.transport.shm_region()) else {
+                log::error!("persistent target exited while running seed {}--restarting it for the next seed", seed.display());
+                let status = server.stop();
+                if watchdog.is_some_and(Watchdog::stop) {
+                    break Outcome::Timeout
+                }
+                break status.map_or(Outcome::Skipped, Outcome::from)
+            };
+
+            match message.body {
+                Body::DumpComplete { seed: Some(id) } if message.pid == server.process.id() && id == seed_id => {
+                    self.server = Some(server);
+                    break Outcome::Ok
+                }
+                _ => on_message(message),
+            }
+        };
+
+        Run {
+            outcome,
+            runtime: start.elapsed(),
+            max_rss: None,
+            stderr: self.transport.take_stderr(),
+        }
+    }
+}
+
+impl Drop for PersistentExecutor {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.stop();
+        }
+    }
+}
+
+/// The state shared by every process an executor launches: the environment telling the preload
+/// how to reach us, the optional shared-memory region, and the file their stderr is captured in.
+struct Transport {
+    shm: Option<(OwnedFd, ShmRegion)>,
+    stderr: Option<fs::File>,
+}
+
+impl Transport {
+    fn new(target: &Target) -> Self {
+        Self {
+            shm: target.shm_size.map(create_shm_region),
+            stderr: target.capture_stderr.then(create_stderr_file),
+        }
+    }
+
+    fn shm_region(&self) -> Option<&ShmRegion> {
+        self.shm.as_ref().map(|(_, region)| region)
+    }
+
+    /// Discards the contents of the shared-memory region. Only safe once no process is writing to it.
+    fn reset(&self) {
+        if let Some(region) = self.shm_region() {
+            region.reset();
+        }
+        self.clear_stderr();
+    }
+
+    /// Returns whatever the target wrote to stderr since it was last taken, if it's captured.
+    fn take_stderr(&self) -> Option<Vec<u8>> {
+        let file = self.stderr.as_ref()?;
+        let len = file.metadata().unwrap().len();
+        if len > STDERR_LIMIT {
+            log::warn!("target wrote {} bytes to stderr--keeping the first {}", len, STDERR_LIMIT);
+        }
+        let mut stderr = vec![0; len.min(STDERR_LIMIT) as usize];
+        // Reading at an offset leaves the file position the target shares with us alone
+        let read = file.read_at(&mut stderr, 0).unwrap();
+        stderr.truncate(read);
+        self.clear_stderr();
+        Some(stderr)
+    }
+
+    /// Empties the stderr file and rewinds the file position, which every process writing to it
+    /// shares, so that their next writes land at its start.
+    fn clear_stderr(&self) {
+        if let Some(mut file) = self.stderr.as_ref() {
+            file.set_len(0).unwrap();
+            file.rewind().unwrap();
+        }
+    }
+
+    /// Builds the command that launches the target on the seed at `input` (if it's known up front)
+    /// under the preload, reporting to `ipc_pipe`.
+    fn command(&self, target: &Target, input: Option<&Path>, ipc_pipe: &PipeWriter) -> (Command, Vec<FdMapping>) {
+        let (cmd, cmd_args) = target.command_line(input);
+
+        let mut fd_mappings = vec! [
+            FdMapping {
+                parent_fd: ipc_pipe.as_raw_fd(),
+                child_fd: ipc_pipe.as_raw_fd(),
+            }
+        ];
+
+        let stderr = match &self.stderr {
+            Some(file) => {
+                self.clear_stderr();
+                Stdio::from(file.try_clone().unwrap())
+            }
+            None => Stdio::null(),
+        };
+
+        let mut command = Command::new(cmd);
+        command.args(cmd_args)
+            .stdout(Stdio::null())
+            .stderr(stderr)
+            .env("LD_PRELOAD", &target.preload_path)
+            .env(protocol::PIPE_FD_ENV, format!("{}", ipc_pipe.as_raw_fd()))
+            .env(protocol::VERSION_ENV, format!("{}", protocol::VERSION))
+            .env(protocol::CAPTURE_ENV, target.capture.as_env_str())
+            .env(protocol::FALLBACK_ENV, target.fallback.to_string());
+
+        if let Some(Signal(signal)) = target.dump_signal {
+            command.env(protocol::DUMP_SIGNAL_ENV, format!("{}", signal));
+        }
+
+        if let Some(port) = target.desock {
+            command.env(protocol::DESOCK_ENV, format!("{}", port));
+            if target.frames_stdin() {
+                command.env(protocol::DESOCK_FRAMED_ENV, "1");
+            }
+        }
+
+        if !target.limits.is_empty() {
+            let limits = target.limits;
+            unsafe { command.pre_exec(move || limits.apply()) };
+        }
+
+        if let Some((shm_fd, shm_region)) = &self.shm {
+            shm_region.reset();
+            command.env(shm::SHM_FD_ENV, format!("{}", shm_fd.as_raw_fd()));
+            fd_mappings.push(FdMapping {
+                parent_fd: shm_fd.as_raw_fd(),
+                child_fd: shm_fd.as_raw_fd(),
+            });
+        }
+
+        (command, fd_mappings)
+    }
+}
+
+/// The receiving end of the IPC pipe, which takes care of the protocol handshake and reassembles
+/// `.gcda` files that were streamed in chunks.
+struct Channel {
+    reader: FrameReader<PipeReader>,
+    greeted_pids: HashSet<u32>,
+    /// Streamed files still being received, keyed by pid and file number
+    partial: HashMap<(u32, u32), PartialGcda>,
+}
+
+struct PartialGcda {
+    filepath: String,
+    chunks: u32,
+    data: Vec<u8>,
+}
+
+impl Channel {
+    fn new(pipe: PipeReader) -> Self {
+        Self {
+            reader: FrameReader::new(pipe),
+            greeted_pids: HashSet::new(),
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Returns the next message from a process that completed the handshake, or `None` once every
+    /// process holding the pipe has exited.
+    fn recv(&mut self, shm: Option<&ShmRegion>) -> Option<Message> {
+        loop {
+            let message = match self.reader.read_message() {
+                Ok(Some(message)) => message,
+                Ok(None) => return self.close(),
+                Err(e) => {
+                    log::error!("Notify pipe failed during reading of coverage ({})--program likely crashed. Skipping testcase...", e);
+                    return self.close()
+                }
+            };
+
+            let body = match message.body {
+                Body::Hello { version } => {
+                    match protocol::check_version(version) {
+                        Ok(()) => _ = self.greeted_pids.insert(message.pid),
+                        Err(e) => log::error!("process {} rejected: {}", message.pid, e),
+                    }
+                    continue
+                }
+                Body::Error(e) => Body::Error(e),
+                _ if !self.greeted_pids.contains(&message.pid) => {
+                    log::warn!("discarding message from process {} that never completed the protocol handshake", message.pid);
+                    continue
+                }
+                Body::GcdaShm { filepath, offset, len } => {
+                    let Some(data) = shm.and_then(|region| region.read(offset, len)) else {
+                        log::error!("process {} referenced shared memory out of bounds for {}--skipping", message.pid, filepath);
+                        continue
+                    };
+                    log::debug!("reading {} bytes of {} from shared memory", len, filepath);
+                    Body::Gcda(Gcda { filepath, data: data.to_vec() })
+                }
+                Body::GcdaBegin { file, filepath } => {
+                    self.partial.insert((message.pid, file), PartialGcda { filepath, chunks: 0, data: Vec::new() });
+                    continue
+                }
+                Body::GcdaChunk { file, seq, data } => {
+                    self.add_chunk(message.pid, file, seq, &data);
+                    continue
+                }
+                Body::GcdaChunkShm { file, seq, offset, len } => {
+                    match shm.and_then(|region| region.read(offset, len)) {
+                        Some(data) => self.add_chunk(message.pid, file, seq, data),
+                        None => {
+                            log::error!("process {} referenced shared memory out of bounds for a chunk of file {}--discarding it", message.pid, file);
+                            self.partial.remove(&(message.pid, file));
+                        }
+                    }
+                    continue
+                }
+                Body::GcdaEnd { file, chunks } => {
+                    let Some(partial) = self.partial.remove(&(message.pid, file)) else {
+                        log::error!("process {} ended file {} without streaming all of it--skipping", message.pid, file);
+                        continue
+                    };
+                    if partial.chunks != chunks {
+                        log::error!("process {} sent {} of {} chunks of {}--skipping", message.pid, partial.chunks, chunks, partial.filepath);
+                        continue
+                    }
+                    log::debug!("reassembled {} bytes of {} from {} chunks", partial.data.len(), partial.filepath, chunks);
+                    Body::Gcda(Gcda { filepath: partial.filepath, data: partial.data })
+                }
+                body => body,
+            };
+
+            return Some(Message { body, ..message })
+        }
+    }
+
+    /// Appends the chunk numbered `seq` to a streamed file, discarding the file if a chunk is
+    /// missing or out of order.
+    fn add_chunk(&mut self, pid: u32, file: u32, seq: u32, data: &[u8]) {
+        let Some(partial) = self.partial.get_mut(&(pid, file)) else {
+            log::error!("process {} sent a chunk of file {} that it never began--discarding it", pid, file);
+            return
+        };
+
+        if partial.chunks != seq {
+            log::error!("process {} sent chunk {} of {} out of order (expected {})--skipping", pid, seq, partial.filepath, partial.chunks);
+            self.partial.remove(&(pid, file));
+            return
+        }
+
+        partial.data.extend_from_slice(data);
+        partial.chunks += 1;
+    }
+
+    /// Called once the pipe is closed; any file still being streamed is incomplete.
+    fn close(&mut self) -> Option<Message> {
+        for ((pid, _), partial) in self.partial.drain() {
+            log::warn!("process {} exited partway through sending {}--skipping", pid, partial.filepath);
+        }
+        None
+    }
+}
+
+/// Waits for `process` to exit like `Child::wait()`, also returning the peak RSS in KiB of it and
+/// the children it waited for, unless its status was already collected.
+fn wait(process: &mut Child) -> (ExitStatus, Option<u64>) {
+    let mut wait_status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        if unsafe { libc::wait4(process.id() as libc::pid_t, &mut wait_status, 0, &mut usage) } >= 0 {
+            return (ExitStatus::from_raw(wait_status), Some(usage.ru_maxrss as u64))
+        }
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            // Reaped by an earlier `Child::try_wait()`, which kept the status
+            return (process.wait().unwrap(), None)
+        }
+    }
+}
+
+/// How much of what the target writes to stderr for a seed is kept, from the start (where the
+/// first sanitizer report is)
+const STDERR_LIMIT: u64 = 1 << 20;
+
+/// Creates an anonymous file for the stderr of target processes.
+fn create_stderr_file() -> fs::File {
+    let fd = unsafe { libc::memfd_create(c"quikcov-stderr".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        panic!("failed to create stderr file: {}", io::Error::last_os_error());
+    }
+    unsafe { fs::File::from_raw_fd(fd) }
+}
+
+/// Creates an anonymous shared-memory region of `len` bytes to be inherited by target processes.
+fn create_shm_region(len: usize) -> (OwnedFd, ShmRegion) {
+    unsafe {
+        let fd = libc::memfd_create(c"quikcov-gcda".as_ptr(), libc::MFD_CLOEXEC);
+        if fd < 0 {
+            panic!("failed to create shared memory region: {}", std::io::Error::last_os_error());
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+
+        if libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) != 0 {
+            panic!("failed to size shared memory region: {}", std::io::Error::last_os_error());
+        }
+
+        let base = libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd.as_raw_fd(), 0);
+        if base == libc::MAP_FAILED {
+            panic!("failed to map shared memory region: {}", std::io::Error::last_os_error());
+        }
+
+        let region = ShmRegion::init(base as *mut u8, len).expect("shared memory region too small");
+        (fd, region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn substitutes_paths_that_arent_utf8() {
+        let input = Path::new(OsStr::from_bytes(b"/queue/id:\xff\xfe"));
+        assert_eq!(substitute_input("@@", input).as_bytes(), b"/queue/id:\xff\xfe");
+        assert_eq!(substitute_input("--in=@@,@@", input).as_bytes(), b"--in=/queue/id:\xff\xfe,/queue/id:\xff\xfe");
+        assert_eq!(substitute_input("--verbose", input), "--verbose");
+    }
+}
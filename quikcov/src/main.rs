@@ -1,259 +1,654 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs;
-use std::io::Read;
-use std::os::fd::AsRawFd;
+use std::io::Write;
 use std::os::unix::prelude::OsStrExt;
-use std::process::{Command, Stdio};
-
-use clap::Parser;
-use command_fds::FdMapping;
-use command_fds::CommandFdExt;
-use fxhash::FxBuildHasher;
-use quikcov_common::prelude::*;
-use serde::{Deserialize, Serialize};
-
-const QUIKCOV_PIPE_ENV: &str = "QUIKCOV_LDPRELOAD_PIPE_FD";
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{CommandFactory, Parser, Subcommand};
+use quikcov_common::protocol::{Body, Fallback, Gcda};
+use serde::Serialize;
+
+use config::{Config, TargetConfig};
+use coverage::{Coverage, CoverageArgs};
+use results::{CoverageOne, CoverageProcess, ResultSet};
+use matcher::Builders;
+use executor::{CaptureMode, Executor, ForkserverExecutor, ForkserverMode, InputMode, NetworkExecutor, Outcome, PersistentExecutor, ResourceLimits, SpawnExecutor, Target};
+use sanitizer::Report;
+use net::{NetAddress, NetInput};
+use preload::ExtractedPreload;
+use replay::Event;
+use seed::SeedFormat;
+use signal::Signal;
+use walk::Filter;
+
+mod config;
+mod coverage;
+mod executor;
+mod matcher;
+mod net;
+mod pcap;
+mod preload;
+mod render;
+mod replay;
+mod results;
+mod sanitizer;
+mod seed;
+mod signal;
+mod walk;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Replay a queue of seeds against the target, recording the coverage after each
+    Run(RunArgs),
+    /// Compute coverage from .gcda files already on disk (e.g. from test runs without the preload),
+    /// or render existing results in another format
+    Report(ReportArgs),
+    /// Combine several sets of results into one
+    Merge(MergeArgs),
+    /// Compare the coverage of two sets of results
+    Diff(DiffArgs),
+    /// Print the coverage of a source file, or of one of its functions
+    Show(ShowArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
 /*
     /// The directory containing the source code of the program
     #[arg(long, value_name = "PATH")]
     source_path: String,
 */
-    /// The directory containing .gcno and .gcda files for the program
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(flatten)]
+    coverage: CoverageArgs,
+    /// The LD_PRELOAD library to load instead of the one built into quikcov
     #[arg(long, value_name = "PATH")]
-    cov_path: String,
-    /// The LD_PRELOAD library to load
-    #[arg(long, value_name = "PATH")]
-    preload_path: String,
+    preload_path: Option<PathBuf>,
     // The directory containing seed files to be tested in alphabetic order
     #[arg(long, value_name = "PATH")]
-    seed_queue: String,
+    seed_queue: Option<PathBuf>,
     /// The directory to store results in
     #[arg(short, long, value_name = "PATH")]
-    output: String,
-    /// Instructs quikcov to prepend any absolute path reported in .gcno/.gcda files to the function location
-    #[arg(short, long)]
-    abs_path: bool,
-    /// The command (and optionally arguments) that will run fuzzing
-    #[arg(required = true)]
+    output: Option<PathBuf>,
+    /// Additionally report the coverage of each process spawned by a seed over the .gcda files it
+    /// wrote (in `<idx>.processes.json`)
+    #[arg(long)]
+    per_process: bool,
+    /// Size in MiB of a shared-memory region used to pass .gcda contents instead of the pipe
+    #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u64).range(1..))]
+    shm_size: Option<u64>,
+    /// Start the target once and fork it at the given point for every seed instead of re-executing it
+    #[arg(long, value_name = "POINT")]
+    forkserver: Option<ForkserverMode>,
+    /// How the preload captures coverage (`gcov-info` requires the target to export `__gcov_master`)
+    #[arg(long, value_name = "MODE", default_value = "file-io")]
+    capture: CaptureMode,
+    /// What the preload does with coverage it can't send back: `passthrough` (write the .gcda file
+    /// as usual), `spool:<dir>` or `drop`
+    #[arg(long, value_name = "FALLBACK", default_value = "passthrough")]
+    fallback: Fallback,
+    /// Run every seed in a single process through its exported `LLVMFuzzerTestOneInput()`
+    #[arg(long, conflicts_with = "forkserver")]
+    persistent: bool,
+    /// How seeds are given to the target (`file` by default if the command contains `@@`, else `stdin`)
+    #[arg(long, value_name = "MODE")]
+    input_mode: Option<InputMode>,
+    /// Run the target as a server and send each seed to it over loopback at `<tcp|udp|sctp>:<port>`
+    #[arg(long, value_name = "ADDRESS", conflicts_with_all = ["forkserver", "persistent", "input_mode"])]
+    net: Option<NetAddress>,
+    /// Split messages sent over the network into packets of at most this many bytes
+    #[arg(long, value_name = "BYTES", requires = "net", value_parser = clap::value_parser!(u32).range(1..))]
+    packet_size: Option<u32>,
+    /// The payload protocol identifier of SCTP messages (e.g. 18 for S1AP)
+    #[arg(long, value_name = "PPID", requires = "net", default_value_t = 0)]
+    sctp_ppid: u32,
+    /// The signal that makes a server (or a seed that timed out) dump its coverage and exit
+    #[arg(long, value_name = "SIGNAL", default_value = "TERM")]
+    dump_signal: Signal,
+    /// Milliseconds each seed may run before the target is sent its dump signal, and killed if it
+    /// still hasn't exited shortly after
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: Option<u64>,
+    /// Limit on the target's address space (`RLIMIT_AS`) in MiB, which targets built with ASan
+    /// always exceed
+    #[arg(long, value_name = "MIB")]
+    mem_limit: Option<u64>,
+    /// Limit on the CPU time of each target process (`RLIMIT_CPU`) in seconds
+    #[arg(long, value_name = "SECONDS")]
+    cpu_limit: Option<u64>,
+    /// Limit on the size of the target's core dumps (`RLIMIT_CORE`) in MiB, 0 disabling them
+    #[arg(long, value_name = "MIB")]
+    core_limit: Option<u64>,
+    /// Have the preload fake the sockets the target binds to this port (0 for any), serving each
+    /// seed as the one connection (or datagram) they ever receive
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["net", "persistent", "input_mode"])]
+    desock: Option<u16>,
+    /// How seeds are split into the messages delivered one at a time over stdin or the network
+    /// (captures only yield the packets sent to the `--net` or `--desock` port, if any)
+    #[arg(long, value_name = "FORMAT", default_value = "raw", conflicts_with = "persistent")]
+    seed_format: SeedFormat,
+    /// Run this many seeds at once, each on a target of its own; coverage is still accumulated in
+    /// seed order
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..), conflicts_with = "net")]
+    jobs: u16,
+    /// Milliseconds to wait between the messages of a seed
+    #[arg(long, value_name = "MS", default_value_t = 0, conflicts_with_all = ["forkserver", "persistent"])]
+    message_delay: u64,
+    /// Capture what the target writes to stderr for each seed (written to `<idx>.stderr`), and
+    /// record the first sanitizer report in it
+    #[arg(long)]
+    capture_stderr: bool,
+    /// Set a variable in the target's environment (may be repeated)
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+    /// The command (and optionally arguments) that will run fuzzing; any `@@` is replaced with the
+    /// path of the seed
     fuzz_command: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize)]
-struct Gcda {
-    filepath: String,
-    data: Vec<u8>,
+/// Where defaults for the other flags are read from
+#[derive(clap::Args, Debug)]
+struct ConfigArgs {
+    /// A quikcov.toml declaring how targets are run and where their coverage is; flags given on
+    /// the command line override it
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// The target of the configuration to use, if it declares several
+    #[arg(long, value_name = "NAME", requires = "config")]
+    target: Option<String>,
+}
+
+impl ConfigArgs {
+    /// Reads the configuration of the chosen target, if a configuration was given, exiting with an
+    /// error if it can't.
+    fn load(&self) -> Option<TargetConfig> {
+        let path = self.config.as_ref()?;
+        let target = Config::load(path).and_then(|config| config.target(self.target.as_deref()));
+        Some(target.unwrap_or_else(|e| Cli::command().error(clap::error::ErrorKind::InvalidValue, e).exit()))
+    }
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected `<name>=<value>`, got `{}`", s)),
+    }
+}
+
+/// Arguments of `report`
+#[derive(clap::Args, Debug)]
+struct ReportArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(flatten)]
+    coverage: CoverageArgs,
+    /// The directory holding the .gcda files, either where the program wrote them or a copy of the
+    /// tree they were relocated to (as with `GCOV_PREFIX`)
+    #[arg(long, value_name = "PATH", required_unless_present = "results")]
+    gcda_dir: Option<PathBuf>,
+    /// The directory of earlier results (from `run`, `report` or `merge`) to render, instead of
+    /// computing coverage from .gcda files
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["gcda_dir", "cov_path", "config"])]
+    results: Option<PathBuf>,
+    /// What to render the coverage as
+    #[arg(long, value_name = "FORMAT", default_value = "results")]
+    format: ReportFormat,
+    /// The directory to store results in or, for other formats, the file to write (stdout if not given)
+    #[arg(short, long, value_name = "PATH", required_if_eq("format", "results"))]
+    output: Option<PathBuf>,
+}
+
+/// What `report` renders coverage as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ReportFormat {
+    /// A directory of results, as written by `run`
+    Results,
+    /// An LCOV tracefile
+    Lcov,
+    /// A table of the coverage of every file
+    Summary,
+}
+
+/// Arguments of `merge`
+#[derive(clap::Args, Debug)]
+struct MergeArgs {
+    /// The directories of the results to merge
+    #[arg(required = true, num_args = 2.., value_name = "RESULTS")]
+    results: Vec<PathBuf>,
+    /// The directory to store the merged results in
+    #[arg(short, long, value_name = "PATH")]
+    output: PathBuf,
+}
+
+/// Arguments of `diff`
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// The directory of the results to compare against
+    #[arg(value_name = "BASE")]
+    base: PathBuf,
+    /// The directory of the results to compare
+    #[arg(value_name = "HEAD")]
+    head: PathBuf,
+}
+
+/// Arguments of `show`
+#[derive(clap::Args, Debug)]
+struct ShowArgs {
+    /// The directory of the results to show
+    #[arg(value_name = "RESULTS")]
+    results: PathBuf,
+    /// The source file to show, by its path or any suffix of it
+    #[arg(value_name = "FILE")]
+    file: String,
+    /// Only show this function of the file
+    #[arg(long, value_name = "NAME")]
+    function: Option<String>,
 }
 
 fn main() {
     env_logger::init();
-    let args = Args::parse();
-
-    // Clear any old .gcda files
-    Command::new("find")
-        .args([args.cov_path.as_str(), "-name", "*.gcda", "-delete"])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .output().unwrap();
-
-    // Gather all .gcno files
-    let gcno_output = Command::new("find")
-        .args([args.cov_path.as_str(), "-name", "*.gcno"])
-        .stderr(Stdio::null())
-        .output().unwrap();
-
-    let mut cov_builders = HashMap::with_hasher(FxBuildHasher::default());
-
-    for cov_path in String::from_utf8(gcno_output.stdout).unwrap().split('\n') {
-        let gcno_file = cov_path.trim();
-        if gcno_file.is_empty() {
-            continue
-        }
 
-        log::debug!("reading .gcno file \"{}\"", gcno_file);
+    match Cli::parse().command {
+        Command::Run(args) => run(args),
+        Command::Report(args) => report(args),
+        Command::Merge(args) => merge(args),
+        Command::Diff(args) => diff(args),
+        Command::Show(args) => show(args),
+    }
+}
+
+/// Reads the result set in `dir`, exiting with an error if it can't.
+fn load_results(dir: &Path) -> ResultSet {
+    ResultSet::load(dir).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1)
+    })
+}
 
-        let gcno_bytes = fs::read(gcno_file).unwrap();
-        if gcno_bytes.is_empty() {
-            continue
+/// Returns `value`, exiting with a usage error if `flag` was given neither on the command line nor
+/// in the configuration.
+fn required<T>(value: Option<T>, flag: &str) -> T {
+    value.unwrap_or_else(|| {
+        Cli::command().error(
+            clap::error::ErrorKind::MissingRequiredArgument,
+            format!("`{}` is required (on the command line or in the configuration)", flag),
+        ).exit()
+    })
+}
+
+/// Converts `mib` MiB given to `flag` into bytes, exiting with a usage error if that doesn't fit.
+fn mib_to_bytes<T: TryFrom<u64>>(mib: u64, flag: &str) -> T {
+    mib.checked_mul(1 << 20).and_then(|bytes| T::try_from(bytes).ok()).unwrap_or_else(|| {
+        Cli::command().error(clap::error::ErrorKind::ValueValidation, format!("`{}` is too large", flag)).exit()
+    })
+}
+
+/// Reads the program's .gcno files, exiting with a usage error if the filters are malformed.
+fn load_coverage(args: &CoverageArgs, clear_gcda: bool) -> Coverage {
+    if args.cov_path.is_empty() {
+        Cli::command().error(clap::error::ErrorKind::MissingRequiredArgument, "at least one `--cov-path` is required").exit()
+    }
+    let filter = Filter::new(&args.include, &args.exclude).unwrap_or_else(|e| {
+        Cli::command().error(clap::error::ErrorKind::ValueValidation, e).exit()
+    });
+    Coverage::load(args, &filter, clear_gcda)
+}
+
+fn run(mut args: RunArgs) {
+    let mut env = BTreeMap::new();
+    if let Some(config) = args.config.load() {
+        config.apply_coverage(&mut args.coverage);
+        if args.fuzz_command.is_empty() {
+            args.fuzz_command = config.command;
         }
+        args.preload_path = args.preload_path.or(config.preload_path);
+        args.seed_queue = args.seed_queue.or(config.seed_queue);
+        args.output = args.output.or(config.output);
+        args.input_mode = args.input_mode.or(config.input_mode);
+        args.timeout = args.timeout.or(config.timeout);
+        env = config.env;
+    }
+    env.extend(args.env.iter().cloned());
 
-        let gcno = Gcno::from_slice(&gcno_bytes).unwrap();
+    let seed_queue = required(args.seed_queue.clone(), "--seed-queue");
+    let output = required(args.output.clone(), "--output");
+    if args.fuzz_command.is_empty() {
+        Cli::command().error(clap::error::ErrorKind::MissingRequiredArgument, "the fuzz command is required (on the command line or in the configuration)").exit()
+    }
 
-        // FIXME: this is brittle if any other part of the file path has .gcno in it
+    // Targets inherit our environment, which is also what their .gcda files are relocated by
+    for (name, value) in &env {
+        std::env::set_var(name, value);
+    }
+
+    let mut coverage = load_coverage(&args.coverage, true);
+
+    // Each process of each seed gets a clean copy of the builder of every .gcda file it reports
+    let pristine_builders = args.per_process.then(|| coverage.builders.clone());
+
+    // clap only sees the command line, so an input mode from the configuration is checked here
+    if args.input_mode.is_some() && (args.net.is_some() || args.desock.is_some()) {
+        Cli::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "`--input-mode` (on the command line or in the configuration) can't be used with `--net` or `--desock`",
+        ).exit();
+    }
 
-        let mut gcda_file = cov_path.replace(".gcno", ".gcda");
-        if args.abs_path {
-            let Some(cwd_path) = gcno.cwd.clone() else {
-                panic!("abs-path flag set but no cwd located in .gcno files");
-            };
-            gcda_file = format!("{}/{}", cwd_path, gcda_file).replace("//", "/");
+    let input_mode = args.input_mode.unwrap_or_else(|| {
+        // Desocketed targets read their seed from stdin, whatever their arguments
+        if args.desock.is_none() && args.fuzz_command.iter().any(|arg| arg.contains(executor::INPUT_PLACEHOLDER)) {
+            InputMode::File
+        } else {
+            InputMode::Stdin
         }
+    });
 
-        cov_builders.insert(gcda_file, FileCovBuilder::new(gcno));
+    if input_mode == InputMode::File && (args.persistent || args.forkserver == Some(ForkserverMode::StdinRead)) {
+        Cli::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "seeds can only be given as files when each is run by a new process or forked off at `main()`",
+        ).exit();
     }
 
+    if input_mode == InputMode::File && args.seed_format != SeedFormat::Raw {
+        Cli::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "seeds can only be split into messages when delivered over stdin or the network",
+        ).exit();
+    }
+
+    // A forkserver's children read the staged seed in one go, so only desocketing keeps the
+    // messages apart
+    if args.forkserver.is_some() && args.seed_format != SeedFormat::Raw && args.desock.is_none() {
+        Cli::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "seeds can only be split into messages for a forkserver when its sockets are faked with `--desock`",
+        ).exit();
+    }
+
+    let shm_size = args.shm_size.map(|mib| mib_to_bytes(mib, "--shm-size"));
+    let limits = ResourceLimits {
+        address_space: args.mem_limit.map(|mib| mib_to_bytes(mib, "--mem-limit")),
+        cpu: args.cpu_limit,
+        core: args.core_limit.map(|mib| mib_to_bytes(mib, "--core-limit")),
+    };
+
+    // Kept until the end of the run, when the extracted copy is removed
+    let extracted_preload = args.preload_path.is_none().then(|| {
+        ExtractedPreload::extract().unwrap_or_else(|e| panic!("failed to extract the preload library: {}", e))
+    });
+    let preload_path = args.preload_path.clone().unwrap_or_else(|| extracted_preload.as_ref().unwrap().path().to_path_buf());
+
+    let target = Target {
+        command: args.fuzz_command.clone(),
+        preload_path,
+        shm_size,
+        capture: args.capture,
+        fallback: args.fallback.clone(),
+        input_mode,
+        seed_format: args.seed_format,
+        message_delay: Duration::from_millis(args.message_delay),
+        dump_signal: (args.net.is_some() || args.timeout.is_some()).then_some(args.dump_signal),
+        desock: args.desock,
+        timeout: args.timeout.map(Duration::from_millis),
+        limits,
+        capture_stderr: args.capture_stderr,
+    };
+
+    // Each worker stages its seeds for a forkserver in a file of its own
+    let new_executor = |worker: usize| -> Box<dyn Executor> {
+        let target = target.clone();
+        match args.forkserver {
+            None if args.net.is_some() => Box::new(NetworkExecutor::new(target, NetInput {
+                address: args.net.unwrap(),
+                packet_size: args.packet_size.map(|size| size as usize),
+                sctp_ppid: args.sctp_ppid,
+            })),
+            Some(mode) => Box::new(ForkserverExecutor::new(target, mode, output.join(format!(".cur_input.{}", worker)))),
+            None if args.persistent => Box::new(PersistentExecutor::new(target)),
+            None => Box::new(SpawnExecutor::new(target)),
+        }
+    };
+
     // Collect list of files to run fuzzer on
-    let mut sorted_seed_files: Vec<_> = fs::read_dir(args.seed_queue).unwrap().map(|file| file.unwrap()).collect();
+    let mut sorted_seed_files: Vec<_> = fs::read_dir(&seed_queue).unwrap().map(|file| file.unwrap()).collect();
     sorted_seed_files.sort_by_key(|file| file.path());
 
-    let mut prev_total_covered = 0;
-    for (idx, seed_file) in sorted_seed_files.into_iter().enumerate() {
-        let seed_pathname = seed_file.path().to_str().unwrap().to_string();
-        if seed_pathname.contains("README.md") || seed_file.path().is_dir() || seed_file.path().file_name().unwrap().as_bytes()[0] == b'.' {
-            continue // Ignore README, dirs, and hidden files
-        }
+    // Seeds keep the index of their place in the queue, skipped files included
+    let (seed_idxs, seed_paths): (Vec<_>, Vec<_>) = sorted_seed_files.into_iter().enumerate()
+        .map(|(idx, seed_file)| (idx, seed_file.path()))
+        .filter(|(_, path)| {
+            // Ignore README, dirs, and hidden files
+            !(path.to_string_lossy().contains("README.md") || path.is_dir() || path.file_name().unwrap().as_bytes()[0] == b'.')
+        })
+        .unzip();
 
-        log::info!("Testing seed file \"{}\"", seed_pathname);
-        let cmd = &args.fuzz_command[0]; // FIXME: brittle
-        let cmd_args = &args.fuzz_command[1..];
-
-        let (mut parent_read_pipe, child_write_pipe) = os_pipe::pipe().unwrap();
-        let mut process = Command::new(cmd)
-            .args(cmd_args)
-            .env("LD_PRELOAD", &args.preload_path)
-            .env(QUIKCOV_PIPE_ENV, format!("{}", child_write_pipe.as_raw_fd()))
-            .fd_mappings(vec! [
-                FdMapping {
-                    parent_fd: child_write_pipe.as_raw_fd(),
-                    child_fd: child_write_pipe.as_raw_fd(),
-                }
-            ]).unwrap()
-            .stdin(Stdio::from(fs::File::open(seed_pathname).unwrap()))
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn().unwrap();
-        drop(child_write_pipe);
-
-        let mut gcda_bytes = Vec::new();
-
-        let mut more_to_read = [0u8; 1];
-        while parent_read_pipe.read(more_to_read.as_mut_slice()).unwrap() != 0 {
-            let mut length_arr = [0u8; 4];
-            if let Err(e) = parent_read_pipe.read_exact(&mut length_arr) {
-                log::error!("Notify pipe failed during reading of coverage ({:?})--program likely crashed. Skipping testcase...", e);
-                break
-            }
-            let length = u32::from_be_bytes(length_arr) as usize;
+    // One line per seed run, written as we go so that it survives an interrupted replay
+    fs::create_dir_all(&output).unwrap();
+    let mut outcomes = fs::File::create(output.join("outcomes.jsonl")).unwrap();
 
-            if length > gcda_bytes.len() {
-                gcda_bytes.reserve(length - gcda_bytes.len());
-                gcda_bytes.extend(std::iter::repeat(0u8).take(length - gcda_bytes.len()));
-            }
+    let mut prev_total_covered = 0;
+    // The coverage of each process of the seed being handed on
+    let mut process_builders = BTreeMap::new();
+    replay::replay(&seed_paths, args.jobs as usize, &new_executor, &mut |i, event| {
+        let run = match event {
+            Event::Message(message) => {
+                let gcda = match message.body {
+                    Body::Gcda(gcda) => gcda,
+                    Body::Error(e) => {
+                        log::error!("process {} reported an error: {}", message.pid, e);
+                        return
+                    }
+                    Body::DumpComplete { .. } => {
+                        log::debug!("process {} finished dumping coverage", message.pid);
+                        return
+                    }
+                    Body::ExitStatus(status) => {
+                        log::debug!("process {} exited with status {}", message.pid, status);
+                        return
+                    }
+                    body => {
+                        log::warn!("unexpected message from process {}: {:?}", message.pid, body);
+                        return
+                    }
+                };
+
+                log::info!("received .gcda file: {:?} (pid {}, ppid {})", &gcda.filepath, message.pid, message.ppid);
+
+                let Some(filepath) = coverage.matcher.resolve(&gcda, &coverage.builders) else {
+                    log::warn!("no .gcno file matches {}--skipping", &gcda.filepath);
+                    return
+                };
+                let builder = coverage.builders.get_mut(filepath).unwrap();
+
+                if let Err(e) = builder.add_gcda(&gcda.data) {
+                    log::error!(".gcda file couldn't be added to builder: {:?}. Skipping...", e);
+                    return
+                }
 
-            if let Err(e) = parent_read_pipe.read_exact(&mut gcda_bytes[..length]) {
-                log::error!("Notify pipe failed during reading of coverage--program likely crashed. Skipping testcase...");
-                break
+                if let Some(pristine_builders) = &pristine_builders {
+                    let (_, builders) = process_builders.entry(message.pid).or_insert_with(|| (message.ppid, Builders::default()));
+                    let builder = builders.entry(filepath.to_owned()).or_insert_with(|| pristine_builders[filepath].clone());
+                    if let Err(e) = builder.add_gcda(&gcda.data) {
+                        log::error!(".gcda file couldn't be added to the builder of process {}: {:?}. Skipping...", message.pid, e);
+                    }
+                }
+                return
             }
+            Event::Finished(run) => run,
+        };
 
-            let Ok(gcda) = postcard::from_bytes::<Gcda>(&gcda_bytes[..length]) else {
-                log::error!("postcard failed to interpret bytes passed from notify pipe. Skipping testcase...");
-                break
-            };
-
-            log::info!("received .gcda file: {:?}", &gcda.filepath);
+        let idx = seed_idxs[i];
+        let seed_pathname = seed_paths[i].to_string_lossy().into_owned();
 
-            let Some(builder) = cov_builders.get_mut(&gcda.filepath) else {
-                log::warn!("file {} not found--skipping", &gcda.filepath);
-                continue
-            };
+        if run.outcome != Outcome::Ok {
+            log::warn!("seed file \"{}\" ended with {:?}", seed_pathname, run.outcome);
+        }
 
-            if let Err(e) = builder.add_gcda(&gcda.data) {
-                log::error!(".gcda file couldn't be added to builder: {:?}. Skipping...", e);
-                continue
+        let mut sanitizer = None;
+        if let Some(stderr) = &run.stderr {
+            sanitizer = sanitizer::parse(&String::from_utf8_lossy(stderr));
+            if let Some(report) = &sanitizer {
+                log::warn!("seed file \"{}\" triggered {} {}", seed_pathname, report.sanitizer, report.crash_type);
             }
+            std::fs::write(output.join(format!("{}.stderr", idx)), stderr).unwrap();
         }
 
-        let Some(coverage) = cov_builders.iter().map(|(_, builder)| builder.clone().build().unwrap()).reduce(|mut a, b| { a.merge(b).unwrap(); a }) else {
-            panic!("no .gcno files found");
+        let record = SeedRecord {
+            idx,
+            seed: &seed_pathname,
+            outcome: run.outcome,
+            runtime_ms: run.runtime.as_micros() as f64 / 1000.0,
+            max_rss_kb: run.max_rss,
+            sanitizer,
         };
+        writeln!(outcomes, "{}", serde_json::to_string(&record).unwrap()).unwrap();
 
-        let mut total_covered = 0;
-        let mut total_blocks = 0;
-        for file in coverage.files.values() {
-            for function in file.fns.values() {
-                total_covered += function.executed_blocks;
-                total_blocks += function.total_blocks;
-            }
-        }
+        let cumulative = CoverageOne::new(coverage::build(&coverage.builders, &coverage.components));
+        let total_covered = cumulative.covered_blocks;
+        let total_blocks = cumulative.total_blocks;
 
         if prev_total_covered != total_covered {
             prev_total_covered = total_covered;
-            let json_out = serde_json::to_vec(&CoverageOne::new(coverage)).unwrap();
-            std::fs::write(format!("{}/{}.coverage.json", &args.output, idx), json_out).unwrap();
+            let json_out = serde_json::to_vec(&cumulative).unwrap();
+            std::fs::write(output.join(format!("{}.coverage.json", idx)), json_out).unwrap();
+        }
+
+        if !process_builders.is_empty() {
+            let processes: Vec<_> = std::mem::take(&mut process_builders).into_iter().map(|(pid, (ppid, builders))| CoverageProcess {
+                pid,
+                ppid,
+                coverage: CoverageOne::new(coverage::build(&builders, &coverage.components)),
+            }).collect();
+            let json_out = serde_json::to_vec(&processes).unwrap();
+            std::fs::write(output.join(format!("{}.processes.json", idx)), json_out).unwrap();
         }
 
         println!("{}: Covered {} blocks out of {} ({:.2}%)", idx, total_covered, total_blocks, (total_covered * 100) as f64 / (total_blocks as f64));
-        // Make sure the old process has died before starting another
-        process.wait().unwrap();
-    }
+    });
+
+    coverage::build(&coverage.builders, &coverage.components).save(&output);
 }
 
-#[derive(Deserialize, Serialize)]
-struct CoverageOne {
-    covered_blocks: usize,
-    total_blocks: usize,
-    files: HashMap<String, CoverageFile, FxBuildHasher>,
+fn report(mut args: ReportArgs) {
+    if let Some(config) = args.config.load() {
+        config.apply_coverage(&mut args.coverage);
+    }
+
+    let results = match (&args.results, &args.gcda_dir) {
+        (Some(results), _) => load_results(results),
+        (None, Some(gcda_dir)) => read_gcda_dir(&args.coverage, gcda_dir),
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+
+    let rendered = match args.format {
+        ReportFormat::Results => {
+            let output = args.output.unwrap();
+            results.save(&output);
+
+            // Laid out like the results of a replay of a single seed
+            let cumulative = CoverageOne::new(results);
+            fs::write(output.join("0.coverage.json"), serde_json::to_vec(&cumulative).unwrap()).unwrap();
+            println!("0: Covered {} blocks out of {} ({:.2}%)", cumulative.covered_blocks, cumulative.total_blocks, (cumulative.covered_blocks * 100) as f64 / (cumulative.total_blocks as f64));
+            return
+        }
+        ReportFormat::Lcov => render::lcov(&results.combined()),
+        ReportFormat::Summary => render::summary(&results),
+    };
+
+    match args.output {
+        Some(output) => fs::write(output, rendered).unwrap(),
+        None => print!("{}", rendered),
+    }
 }
 
-impl CoverageOne {
-    pub fn new(cov: ProgCoverage) -> Self {
-        let mut covered_blocks = 0;
-        let mut total_blocks = 0;
-        let mut files = HashMap::with_hasher(FxBuildHasher::default());
-        for (name, file) in cov.files {
-            let cov_file = CoverageFile::new(file);
-            covered_blocks += cov_file.covered_blocks;
-            total_blocks += cov_file.total_blocks;
-            files.insert(name, cov_file);
+/// Computes the coverage of the .gcda files under `gcda_dir`.
+fn read_gcda_dir(args: &CoverageArgs, gcda_dir: &Path) -> ResultSet {
+    let mut coverage = load_coverage(args, false);
+    let cwd = std::env::current_dir().unwrap();
+
+    for gcda_file in walk::coverage_files(gcda_dir, &Filter::default()).gcda {
+        let data = match fs::read(&gcda_file) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("failed to read .gcda file {}: {}--skipping", gcda_file.display(), e);
+                continue
+            }
+        };
+
+        // Either the file is where the program wrote it, or the directory stands in for `/`
+        let in_place: PathBuf = cwd.join(&gcda_file).components().collect();
+        let relocated = Path::new("/").join(gcda_file.strip_prefix(gcda_dir).unwrap());
+
+        let mut gcda = Gcda { filepath: in_place.to_string_lossy().into_owned(), data };
+        let mut filepath = coverage.matcher.resolve(&gcda, &coverage.builders).map(str::to_string);
+        if filepath.is_none() {
+            gcda.filepath = relocated.to_string_lossy().into_owned();
+            filepath = coverage.matcher.resolve(&gcda, &coverage.builders).map(str::to_string);
         }
+        let Some(filepath) = filepath else {
+            log::warn!("no .gcno file matches {}--skipping", gcda_file.display());
+            continue
+        };
 
-        Self {
-            covered_blocks,
-            total_blocks,
-            files,
+        log::info!("read .gcda file {} for {}", gcda_file.display(), filepath);
+        if let Err(e) = coverage.builders.get_mut(&filepath).unwrap().add_gcda(&gcda.data) {
+            log::error!(".gcda file couldn't be added to builder: {:?}. Skipping...", e);
         }
     }
+
+    coverage::build(&coverage.builders, &coverage.components)
 }
 
-#[derive(Deserialize, Serialize)]
-struct CoverageFile {
-    covered_blocks: usize,
-    total_blocks: usize,
-    //fns: HashMap<String, CoverageFunction, FxBuildHasher>,
+fn merge(args: MergeArgs) {
+    let mut results = args.results.iter().map(|dir| load_results(dir));
+    let mut merged = results.next().unwrap();
+    for other in results {
+        merged.merge(other);
+    }
+    merged.save(&args.output);
 }
 
-impl CoverageFile {
-    pub fn new(cov: FileCoverage) -> Self {
-        let mut covered_blocks = 0;
-        let mut total_blocks = 0;
-        // let mut functions = HashMap::with_hasher(FxBuildHasher::default());
-        for (fn_name, function) in cov.fns {
-            covered_blocks += function.executed_blocks;
-            total_blocks += function.total_blocks;
-
-            /*
-            functions.insert(fn_name, CoverageFunction {
-                covered_blocks: function.executed_blocks,
-                total_blocks: function.total_blocks,
-            });
-            */
-        }
+fn diff(args: DiffArgs) {
+    print!("{}", render::diff(load_results(&args.base), load_results(&args.head)));
+}
 
-        Self {
-            covered_blocks,
-            total_blocks,
-            // fns: functions,
+fn show(args: ShowArgs) {
+    let results = load_results(&args.results);
+    // Source paths are relative to the directory their component was compiled in
+    let source_dirs: Vec<_> = results.components.values().filter_map(|coverage| coverage.cwd.clone()).collect();
+    let coverage = results.combined();
+    match render::show(&coverage, &source_dirs, &args.file, args.function.as_deref()) {
+        Some(rendered) => print!("{}", rendered),
+        None => {
+            match &args.function {
+                Some(function) => eprintln!("error: no function `{}` in a file matching `{}`", function, args.file),
+                None => eprintln!("error: no file matching `{}`", args.file),
+            }
+            std::process::exit(1)
         }
     }
 }
 
-/*
-#[derive(Deserialize, Serialize)]
-struct CoverageFunction {
-    covered_blocks: usize,
-    total_blocks: usize,
+/// How the run of a seed went, as recorded in `outcomes.jsonl`
+#[derive(Serialize)]
+struct SeedRecord<'a> {
+    idx: usize,
+    seed: &'a str,
+    #[serde(flatten)]
+    outcome: Outcome,
+    runtime_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_rss_kb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sanitizer: Option<Report>,
 }
-*/
@@ -0,0 +1,130 @@
+//! Pairs the `.gcda` files captured from the target with the `.gcno` files they belong to.
+//!
+//! The stamp GCC records in both files decides the match wherever it can, so that coverage still
+//! lands in the right place when the target runs somewhere its build paths don't exist (relocated
+//! build trees, sandboxes, containers). Where stamps are missing (before GCC 11.3) or collide, the
+//! path the file was written to serves as a hint, and objects whose functions don't match those of
+//! the `.gcda` file are ruled out.
+
+use std::collections::HashMap;
+
+use fxhash::FxBuildHasher;
+use quikcov_common::prelude::*;
+use quikcov_common::protocol::Gcda;
+use quikcov_common::reader;
+
+pub type Builders = HashMap<String, FileCovBuilder, FxBuildHasher>;
+
+#[derive(Default)]
+pub struct GcdaMatcher {
+    /// Further paths the `.gcda` file of a builder may be written to, keyed to its path in the
+    /// builders
+    aliases: HashMap<String, String, FxBuildHasher>,
+    /// The builders of the `.gcno` files bearing each stamp
+    stamps: HashMap<u32, Vec<String>, FxBuildHasher>,
+}
+
+impl GcdaMatcher {
+    /// Registers a `.gcno` file whose `.gcda` file is expected at the first of `gcda_files` (or any
+    /// of the others), returning the path its builder should be stored under.
+    pub fn add(&mut self, gcno: &Gcno, mut gcda_files: impl Iterator<Item = String>) -> String {
+        let gcda_file = gcda_files.next().expect("no .gcda path for .gcno file");
+        for alias in gcda_files {
+            self.aliases.insert(alias, gcda_file.clone());
+        }
+
+        if let Some(stamp) = gcno.stamp {
+            self.stamps.entry(stamp).or_default().push(gcda_file.clone());
+        }
+
+        gcda_file
+    }
+
+    /// Returns the path of the builder `gcda` belongs to, if any.
+    pub fn resolve<'a>(&'a self, gcda: &'a Gcda, builders: &Builders) -> Option<&'a str> {
+        let hint = self.aliases.get(&gcda.filepath).unwrap_or(&gcda.filepath);
+        let matches = |path: &String| builders.get(path).is_some_and(|builder| builder.gcno().matches_gcda(&gcda.data).unwrap_or(false));
+
+        let stamp = reader::gcda_stamp(&gcda.data).ok().flatten();
+        let candidates = match stamp.and_then(|stamp| self.stamps.get(&stamp)) {
+            Some(candidates) => candidates,
+            // Nothing to go on but the path
+            None => return builders.contains_key(hint).then_some(hint.as_str()),
+        };
+
+        if candidates.contains(hint) && matches(hint) {
+            return Some(hint)
+        }
+
+        let mut matching = candidates.iter().filter(|path| matches(path));
+        let found = matching.next()?;
+        if matching.next().is_some() {
+            log::warn!("{} matches several .gcno files with the same stamp--attributing it to {}", gcda.filepath, found);
+        } else if found != hint {
+            log::debug!("matched {} to {} by its stamp", gcda.filepath, found);
+        }
+
+        Some(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Notes and counts GCC 12.2 produced for the same object (see `common/tests/gcc12.rs`)
+    const GCNO: &[u8] = include_bytes!("../../common/tests/fixtures/gcc12/sample.gcno");
+    const GCDA: &[u8] = include_bytes!("../../common/tests/fixtures/gcc12/sample.gcda");
+
+    /// Registers the fixture's notes at each of `paths`, with the further paths it may be written
+    /// to
+    fn matcher(paths: &[&[&str]]) -> (GcdaMatcher, Builders) {
+        let mut matcher = GcdaMatcher::default();
+        let mut builders = Builders::default();
+        for gcda_files in paths {
+            let gcno = Gcno::from_slice(GCNO).unwrap();
+            let path = matcher.add(&gcno, gcda_files.iter().map(|path| path.to_string()));
+            builders.insert(path, FileCovBuilder::new(gcno));
+        }
+        (matcher, builders)
+    }
+
+    fn gcda(filepath: &str, data: &[u8]) -> Gcda {
+        Gcda { filepath: filepath.to_string(), data: data.to_vec() }
+    }
+
+    #[test]
+    fn matches_by_stamp_wherever_the_file_was_written() {
+        let (matcher, builders) = matcher(&[&["/build/sample.gcda"]]);
+        let gcda = gcda("/sandbox/1234/sample.gcda", GCDA);
+        assert_eq!(matcher.resolve(&gcda, &builders), Some("/build/sample.gcda"));
+    }
+
+    #[test]
+    fn prefers_the_path_among_matching_stamps() {
+        let (matcher, builders) = matcher(&[&["/a/sample.gcda"], &["/b/sample.gcda", "/b/alias.gcda"]]);
+        assert_eq!(matcher.resolve(&gcda("/b/sample.gcda", GCDA), &builders), Some("/b/sample.gcda"));
+        assert_eq!(matcher.resolve(&gcda("/b/alias.gcda", GCDA), &builders), Some("/b/sample.gcda"));
+        assert_eq!(matcher.resolve(&gcda("/a/sample.gcda", GCDA), &builders), Some("/a/sample.gcda"));
+    }
+
+    #[test]
+    fn rules_out_objects_with_other_functions() {
+        let (matcher, builders) = matcher(&[&["/build/sample.gcda"]]);
+
+        // Same stamp, but a function checksum that doesn't match (see `common/tests/gcc12.rs`)
+        let mut data = GCDA.to_vec();
+        data[44] ^= 1;
+        assert_eq!(matcher.resolve(&gcda("/build/sample.gcda", &data), &builders), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_path_for_unknown_stamps() {
+        let (matcher, builders) = matcher(&[&["/build/sample.gcda"]]);
+
+        let mut data = GCDA.to_vec();
+        data[8] ^= 1;
+        assert_eq!(matcher.resolve(&gcda("/build/sample.gcda", &data), &builders), Some("/build/sample.gcda"));
+        assert_eq!(matcher.resolve(&gcda("/elsewhere/sample.gcda", &data), &builders), None);
+    }
+}
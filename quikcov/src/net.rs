@@ -0,0 +1,304 @@
+//! Delivers seeds to servers over the loopback interface.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpStream, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a server may stay silent before we stop waiting for further responses
+const RESPONSE_IDLE: Duration = Duration::from_millis(100);
+
+// From <netinet/sctp.h>, which the libc crate doesn't cover
+const SOL_SCTP: libc::c_int = 132;
+const SCTP_SNDRCV: libc::c_int = 1;
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct SctpSndrcvinfo {
+    sinfo_stream: u16,
+    sinfo_ssn: u16,
+    sinfo_flags: u16,
+    sinfo_ppid: u32,
+    sinfo_context: u32,
+    sinfo_timetolive: u32,
+    sinfo_tsn: u32,
+    sinfo_cumtsn: u32,
+    sinfo_assoc_id: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetProtocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+/// A loopback port and the protocol it's served over, written as e.g. `tcp:8080` or `sctp:36412`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetAddress {
+    pub protocol: NetProtocol,
+    pub port: u16,
+}
+
+impl FromStr for NetAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((protocol, port)) = s.split_once(':') else {
+            return Err(format!("expected `<tcp|udp|sctp>:<port>`, got `{}`", s))
+        };
+
+        let protocol = match protocol {
+            "tcp" => NetProtocol::Tcp,
+            "udp" => NetProtocol::Udp,
+            "sctp" => NetProtocol::Sctp,
+            _ => return Err(format!("unknown protocol `{}` (expected tcp, udp or sctp)", protocol)),
+        };
+        let port = port.parse().map_err(|_| format!("invalid port `{}`", port))?;
+
+        Ok(Self { protocol, port })
+    }
+}
+
+impl fmt::Display for NetAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = match self.protocol {
+            NetProtocol::Tcp => "tcp",
+            NetProtocol::Udp => "udp",
+            NetProtocol::Sctp => "sctp",
+        };
+        write!(f, "{}:{}", protocol, self.port)
+    }
+}
+
+impl NetAddress {
+    /// Indicates whether some process is accepting connections (or datagrams) on this address.
+    ///
+    /// This goes by the kernel's socket tables rather than by connecting, which the server would
+    /// count as a client of its own.
+    pub fn is_listening(&self) -> bool {
+        match self.protocol {
+            // TCP_LISTEN
+            NetProtocol::Tcp => ["/proc/net/tcp", "/proc/net/tcp6"].iter().any(|table| in_socket_table(table, self.port, "0A")),
+            // Bound UDP sockets are in TCP_CLOSE
+            NetProtocol::Udp => ["/proc/net/udp", "/proc/net/udp6"].iter().any(|table| in_socket_table(table, self.port, "07")),
+            NetProtocol::Sctp => {
+                std::fs::read_to_string("/proc/net/sctp/eps").is_ok_and(|endpoints| has_sctp_endpoint(&endpoints, self.port))
+            }
+        }
+    }
+}
+
+/// Looks for a socket on local port `port` in state `state` in one of the tables of `/proc/net`.
+fn in_socket_table(table: &str, port: u16, state: &str) -> bool {
+    std::fs::read_to_string(table).is_ok_and(|sockets| has_socket(&sockets, port, state))
+}
+
+/// Looks for a socket on local port `port` in state `state` in the contents of a socket table.
+fn has_socket(sockets: &str, port: u16, state: &str) -> bool {
+    // sl local_address rem_address st ...
+    sockets.lines().skip(1).any(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let local_port = fields.get(1)
+            .and_then(|address| address.rsplit_once(':'))
+            .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+        local_port == Some(port) && fields.get(3) == Some(&state)
+    })
+}
+
+/// Looks for an endpoint bound to `port` in the contents of `/proc/net/sctp/eps`.
+fn has_sctp_endpoint(endpoints: &str, port: u16) -> bool {
+    // ENDPT SOCK STY SST HBKT LPORT ...
+    endpoints.lines().skip(1).any(|line| line.split_whitespace().nth(5).and_then(|lport| lport.parse().ok()) == Some(port))
+}
+
+/// How seeds are sent to a server.
+#[derive(Clone, Debug)]
+pub struct NetInput {
+    pub address: NetAddress,
+    /// Splits messages into packets (datagrams, for UDP; messages, for SCTP) of at most this many
+    /// bytes rather than sending each in one go
+    pub packet_size: Option<usize>,
+    /// The payload protocol identifier SCTP messages are sent with
+    pub sctp_ppid: u32,
+}
+
+impl NetInput {
+    /// Connects to the server, sends it the `messages` of a seed (waiting `delay` between each) and
+    /// reads its responses until it closes the connection or goes quiet.
+    pub fn send(&self, messages: &[&[u8]], delay: Duration) -> io::Result<()> {
+        let mut packets = Vec::new();
+        for (i, message) in messages.iter().enumerate() {
+            let first_packet = packets.len();
+            match self.packet_size {
+                Some(size) => packets.extend(message.chunks(size).map(|packet| (packet, Duration::ZERO))),
+                None => packets.push((message, Duration::ZERO)),
+            }
+            if i > 0 {
+                if let Some((_, pause)) = packets.get_mut(first_packet) {
+                    *pause = delay;
+                }
+            }
+        }
+
+        match self.address.protocol {
+            NetProtocol::Tcp => {
+                let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.address.port))?;
+                for (packet, pause) in packets {
+                    std::thread::sleep(pause);
+                    stream.write_all(packet)?;
+                }
+                drain(&mut stream)
+            }
+            NetProtocol::Udp => {
+                let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+                socket.connect((Ipv4Addr::LOCALHOST, self.address.port))?;
+                for (packet, pause) in packets {
+                    std::thread::sleep(pause);
+                    socket.send(packet)?;
+                }
+
+                let mut buf = [0u8; 65536];
+                socket.set_read_timeout(Some(RESPONSE_IDLE))?;
+                while socket.recv(&mut buf).is_ok() {}
+                Ok(())
+            }
+            NetProtocol::Sctp => {
+                // A one-to-one SCTP socket reads and writes like a TCP one
+                let mut stream = TcpStream::from(sctp_connect(self.address.port)?);
+                for (packet, pause) in packets {
+                    std::thread::sleep(pause);
+                    sctp_send(&stream, packet, self.sctp_ppid)?;
+                }
+                drain(&mut stream)
+            }
+        }
+    }
+}
+
+/// Reads whatever the server sends back until it closes the connection or goes quiet, so that it
+/// never blocks on a full socket buffer.
+fn drain(stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(RESPONSE_IDLE))?;
+
+    let mut buf = [0u8; 65536];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => (),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::ConnectionReset) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn sctp_connect(port: u16) -> io::Result<OwnedFd> {
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, libc::IPPROTO_SCTP);
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+
+        let mut address: libc::sockaddr_in = std::mem::zeroed();
+        address.sin_family = libc::AF_INET as libc::sa_family_t;
+        address.sin_port = port.to_be();
+        address.sin_addr.s_addr = u32::from(Ipv4Addr::LOCALHOST).to_be();
+
+        let address_ptr = &address as *const libc::sockaddr_in as *const libc::sockaddr;
+        if libc::connect(fd.as_raw_fd(), address_ptr, std::mem::size_of_val(&address) as libc::socklen_t) != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(fd)
+    }
+}
+
+/// Sends `packet` as one SCTP message carrying the payload protocol identifier `ppid`.
+fn sctp_send(stream: &TcpStream, packet: &[u8], ppid: u32) -> io::Result<()> {
+    let info = SctpSndrcvinfo {
+        sinfo_ppid: ppid.to_be(),
+        ..Default::default()
+    };
+
+    unsafe {
+        // Aligned for the `cmsghdr` at its start
+        let mut control = [0u64; 8];
+        let control_len = libc::CMSG_SPACE(std::mem::size_of::<SctpSndrcvinfo>() as u32) as usize;
+        assert!(control_len <= std::mem::size_of_val(&control));
+
+        let mut iov = libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        };
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control_len as _;
+
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = SOL_SCTP;
+        (*header).cmsg_type = SCTP_SNDRCV;
+        (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<SctpSndrcvinfo>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(header) as *mut SctpSndrcvinfo, info);
+
+        let sent = libc::sendmsg(stream.as_raw_fd(), &message, libc::MSG_NOSIGNAL);
+        if sent < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        if sent as usize != packet.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "SCTP message was only partially sent"))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!("tcp:8080".parse(), Ok(NetAddress { protocol: NetProtocol::Tcp, port: 8080 }));
+        assert_eq!("udp:53".parse(), Ok(NetAddress { protocol: NetProtocol::Udp, port: 53 }));
+        assert_eq!("sctp:36412".parse::<NetAddress>().unwrap().to_string(), "sctp:36412");
+
+        assert!("8080".parse::<NetAddress>().is_err());
+        assert!("quic:443".parse::<NetAddress>().is_err());
+        assert!("tcp:65536".parse::<NetAddress>().is_err());
+        assert!("tcp:".parse::<NetAddress>().is_err());
+    }
+
+    #[test]
+    fn finds_sockets_in_tables() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 40123 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1F91 0100007F:D2F4 01 00000000:00000000 00:00000000 00000000  1000        0 40124 1 0000000000000000 20 4 30 10 -1
+";
+        assert!(has_socket(tcp, 8080, "0A"));
+        // Connected, not listening
+        assert!(!has_socket(tcp, 8081, "0A"));
+        assert!(!has_socket(tcp, 8082, "0A"));
+
+        let tcp6 = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000000000000:0035 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 1234 2 0000000000000000 0
+";
+        assert!(has_socket(tcp6, 53, "07"));
+        // The header alone is no socket
+        assert!(!has_socket(tcp6.lines().next().unwrap(), 53, "07"));
+    }
+
+    #[test]
+    fn finds_sctp_endpoints() {
+        let endpoints = " ENDPT     SOCK   STY SST HBKT LPORT   UID INODE LADDRS
+ffff8880a1b2c3d4 ffff8880a1b2c000 2   10  29   36412     0 51234 127.0.0.1
+";
+        assert!(has_sctp_endpoint(endpoints, 36412));
+        assert!(!has_sctp_endpoint(endpoints, 3641));
+    }
+}
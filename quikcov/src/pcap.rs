@@ -0,0 +1,440 @@
+//! Pulls the application payloads out of pcap and pcapng captures.
+//!
+//! Every TCP segment, UDP datagram and SCTP DATA chunk carrying data counts as a message of its
+//! own; TCP streams aren't reassembled, so retransmissions show up twice. IP fragments past the
+//! first are ignored.
+
+/// The byte order a capture was written in.
+#[derive(Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+}
+
+// Link-layer header types, from https://www.tcpdump.org/linktypes.html
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_SCTP: u8 = 132;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_PACKET: u32 = 2;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+
+/// Returns the payloads of the packets in `capture` (those sent to `port`, if given), in order.
+pub fn payloads(capture: &[u8], port: Option<u16>) -> Result<Vec<&[u8]>, String> {
+    let mut payloads = Vec::new();
+    for (link_type, frame) in frames(capture)? {
+        payloads.extend(frame_payloads(link_type, frame)?.into_iter()
+            .filter(|(dst_port, _)| port.is_none_or(|port| port == *dst_port))
+            .map(|(_, payload)| payload));
+    }
+    Ok(payloads)
+}
+
+/// Splits a capture into its frames and their link-layer header types.
+fn frames(capture: &[u8]) -> Result<Vec<(u32, &[u8])>, String> {
+    let Some(magic) = capture.get(..4) else {
+        return Err("capture is too short for a header".to_string())
+    };
+
+    match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => pcap_frames(capture, Endian::Little),
+        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => pcap_frames(capture, Endian::Big),
+        [0x0a, 0x0d, 0x0d, 0x0a] => pcapng_frames(capture),
+        _ => Err(format!("not a pcap or pcapng capture (magic {:02x?})", magic)),
+    }
+}
+
+fn pcap_frames(capture: &[u8], endian: Endian) -> Result<Vec<(u32, &[u8])>, String> {
+    let header = capture.get(..24).ok_or("pcap header is truncated")?;
+    // The upper bits may hold the FCS length
+    let link_type = endian.u32(&header[20..]) & 0x0fff_ffff;
+
+    let mut frames = Vec::new();
+    let mut rest = &capture[24..];
+    while !rest.is_empty() {
+        let record = rest.get(..16).ok_or_else(|| format!("header of packet {} is truncated", frames.len()))?;
+        let len = endian.u32(&record[8..]) as usize;
+        let frame = rest.get(16..16 + len).ok_or_else(|| format!("packet {} is truncated", frames.len()))?;
+        frames.push((link_type, frame));
+        rest = &rest[16 + len..];
+    }
+
+    Ok(frames)
+}
+
+fn pcapng_frames(capture: &[u8]) -> Result<Vec<(u32, &[u8])>, String> {
+    let mut frames = Vec::new();
+    let mut endian = Endian::Little;
+    let mut interfaces = Vec::new();
+
+    let mut rest = capture;
+    while !rest.is_empty() {
+        let header = rest.get(..12).ok_or("pcapng block is truncated")?;
+
+        // The section header's type reads the same in either byte order
+        if endian.u32(header) == PCAPNG_SECTION_HEADER {
+            // The byte-order magic decides how this section (including its own length) is read
+            endian = match header[8..12] {
+                [0x4d, 0x3c, 0x2b, 0x1a] => Endian::Little,
+                [0x1a, 0x2b, 0x3c, 0x4d] => Endian::Big,
+                _ => return Err("pcapng section has an unknown byte order".to_string()),
+            };
+            interfaces.clear();
+        }
+
+        let block_type = endian.u32(header);
+        let block_len = endian.u32(&header[4..]) as usize;
+        let block = rest.get(..block_len).filter(|_| block_len >= 12).ok_or("pcapng block is truncated")?;
+        let body = &block[8..block_len - 4];
+
+        let interface_frame = |interface: usize, offset: usize, captured: usize| -> Result<(u32, &[u8]), String> {
+            let link_type = *interfaces.get(interface).ok_or_else(|| format!("packet refers to unknown interface {}", interface))?;
+            let frame = body.get(offset..offset + captured).ok_or("pcapng packet is truncated")?;
+            Ok((link_type, frame))
+        };
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = body.get(..2).ok_or("pcapng interface block is truncated")?;
+                interfaces.push(endian.u16(link_type) as u32);
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let fields = body.get(..20).ok_or("pcapng packet is truncated")?;
+                frames.push(interface_frame(endian.u32(fields) as usize, 20, endian.u32(&fields[12..]) as usize)?);
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let fields = body.get(..4).ok_or("pcapng packet is truncated")?;
+                let captured = (endian.u32(fields) as usize).min(body.len() - 4);
+                frames.push(interface_frame(0, 4, captured)?);
+            }
+            PCAPNG_PACKET => {
+                let fields = body.get(..20).ok_or("pcapng packet is truncated")?;
+                frames.push(interface_frame(endian.u16(fields) as usize, 20, endian.u32(&fields[12..]) as usize)?);
+            }
+            _ => (),
+        }
+
+        rest = &rest[block_len..];
+    }
+
+    Ok(frames)
+}
+
+/// Returns the destination port and payload of each message in a frame.
+fn frame_payloads(link_type: u32, frame: &[u8]) -> Result<Vec<(u16, &[u8])>, String> {
+    let packet = match link_type {
+        LINKTYPE_NULL => {
+            // The address family, in the byte order of the machine that captured it
+            let family = frame.get(..4).map(|family| Endian::Little.u32(family).min(Endian::Big.u32(family)));
+            match family {
+                Some(2) => frame.get(4..).map(|packet| (ETHERTYPE_IPV4, packet)),
+                Some(24 | 28 | 30) => frame.get(4..).map(|packet| (ETHERTYPE_IPV6, packet)),
+                _ => None,
+            }
+        }
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = frame.get(12..14).map(|ethertype| Endian::Big.u16(ethertype));
+            let mut offset = 14;
+            while ethertype == Some(ETHERTYPE_VLAN) {
+                ethertype = frame.get(offset + 2..offset + 4).map(|ethertype| Endian::Big.u16(ethertype));
+                offset += 4;
+            }
+            ethertype.zip(frame.get(offset..))
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => match frame.first().map(|byte| byte >> 4) {
+            Some(4) => Some((ETHERTYPE_IPV4, frame)),
+            Some(6) => Some((ETHERTYPE_IPV6, frame)),
+            _ => None,
+        },
+        LINKTYPE_LINUX_SLL => frame.get(14..16).map(|protocol| Endian::Big.u16(protocol)).zip(frame.get(16..)),
+        LINKTYPE_LINUX_SLL2 => frame.get(..2).map(|protocol| Endian::Big.u16(protocol)).zip(frame.get(20..)),
+        _ => return Err(format!("unsupported link-layer header type {}", link_type)),
+    };
+
+    let transport = match packet {
+        Some((ETHERTYPE_IPV4, packet)) => ipv4_payload(packet),
+        Some((ETHERTYPE_IPV6, packet)) => ipv6_payload(packet),
+        _ => None,
+    };
+
+    Ok(match transport {
+        Some((IPPROTO_TCP, segment)) => tcp_payload(segment).into_iter().collect(),
+        Some((IPPROTO_UDP, datagram)) => udp_payload(datagram).into_iter().collect(),
+        Some((IPPROTO_SCTP, packet)) => sctp_payloads(packet),
+        _ => Vec::new(),
+    })
+}
+
+fn ipv4_payload(packet: &[u8]) -> Option<(u8, &[u8])> {
+    let header_len = (*packet.first()? as usize & 0xf) * 4;
+    let total_len = Endian::Big.u16(packet.get(2..4)?) as usize;
+    let fragment_offset = Endian::Big.u16(packet.get(6..8)?) & 0x1fff;
+    if fragment_offset != 0 {
+        return None
+    }
+
+    // Ethernet pads short frames beyond the IP packet
+    let packet = packet.get(..total_len).unwrap_or(packet);
+    Some((*packet.get(9)?, packet.get(header_len..)?))
+}
+
+fn ipv6_payload(packet: &[u8]) -> Option<(u8, &[u8])> {
+    let payload_len = Endian::Big.u16(packet.get(4..6)?) as usize;
+    let mut next_header = *packet.get(6)?;
+    let payload = packet.get(40..)?;
+    let mut payload = payload.get(..payload_len).unwrap_or(payload);
+
+    loop {
+        match next_header {
+            // Hop-by-hop, routing and destination options
+            0 | 43 | 60 => {
+                let len = (*payload.get(1)? as usize + 1) * 8;
+                next_header = *payload.first()?;
+                payload = payload.get(len..)?;
+            }
+            // Fragment
+            44 => {
+                if Endian::Big.u16(payload.get(2..4)?) >> 3 != 0 {
+                    return None
+                }
+                next_header = *payload.first()?;
+                payload = payload.get(8..)?;
+            }
+            _ => return Some((next_header, payload)),
+        }
+    }
+}
+
+fn tcp_payload(segment: &[u8]) -> Option<(u16, &[u8])> {
+    let dst_port = Endian::Big.u16(segment.get(2..4)?);
+    let header_len = (*segment.get(12)? as usize >> 4) * 4;
+    Some((dst_port, segment.get(header_len..)?)).filter(|(_, payload)| !payload.is_empty())
+}
+
+fn udp_payload(datagram: &[u8]) -> Option<(u16, &[u8])> {
+    let dst_port = Endian::Big.u16(datagram.get(2..4)?);
+    let len = Endian::Big.u16(datagram.get(4..6)?) as usize;
+    let payload = datagram.get(8..len.max(8)).or(datagram.get(8..))?;
+    Some((dst_port, payload)).filter(|(_, payload)| !payload.is_empty())
+}
+
+fn sctp_payloads(packet: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut payloads = Vec::new();
+    let Some(dst_port) = packet.get(2..4).map(|port| Endian::Big.u16(port)) else {
+        return payloads
+    };
+
+    let mut chunks = packet.get(12..).unwrap_or_default();
+    while let Some(header) = chunks.get(..4) {
+        let chunk_type = header[0];
+        let len = Endian::Big.u16(&header[2..]) as usize;
+        let Some(chunk) = chunks.get(..len).filter(|_| len >= 4) else {
+            break
+        };
+
+        let data = match chunk_type {
+            // DATA
+            0 => chunk.get(16..),
+            // I-DATA
+            64 => chunk.get(20..),
+            _ => None,
+        };
+        payloads.extend(data.filter(|data| !data.is_empty()).map(|data| (dst_port, data)));
+
+        // Chunks are padded to a multiple of 4 bytes
+        chunks = chunks.get(len.next_multiple_of(4)..).unwrap_or_default();
+    }
+
+    payloads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An IPv4 packet carrying a UDP datagram to `port`
+    fn udp_packet(port: u16, payload: &[u8]) -> Vec<u8> {
+        let udp_len = 8 + payload.len() as u16;
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, IPPROTO_UDP, 0, 0, 127, 0, 0, 1, 127, 0, 0, 1];
+        packet[2..4].copy_from_slice(&(20 + udp_len).to_be_bytes());
+        packet.extend([0x30, 0x39]);
+        packet.extend(port.to_be_bytes());
+        packet.extend(udp_len.to_be_bytes());
+        packet.extend([0, 0]);
+        packet.extend(payload);
+        packet
+    }
+
+    /// An IPv4 packet carrying a TCP segment to `port`
+    fn tcp_packet(port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, IPPROTO_TCP, 0, 0, 127, 0, 0, 1, 127, 0, 0, 1];
+        packet[2..4].copy_from_slice(&(40 + payload.len() as u16).to_be_bytes());
+        packet.extend([0x30, 0x39]);
+        packet.extend(port.to_be_bytes());
+        packet.extend([0; 8]);
+        // Data offset of 5 words, then flags, window, checksum and urgent pointer
+        packet.extend([0x50, 0x18, 0, 0, 0, 0, 0, 0]);
+        packet.extend(payload);
+        packet
+    }
+
+    /// `packet` behind an Ethernet header
+    fn ethernet(packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend(ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend(packet);
+        frame
+    }
+
+    /// A pcap capture of `frames`, written in the byte order `to_bytes` gives
+    fn pcap(link_type: u32, frames: &[Vec<u8>], to_bytes: fn(u32) -> [u8; 4]) -> Vec<u8> {
+        let mut capture = to_bytes(0xa1b2c3d4).to_vec();
+        capture.extend(to_bytes(0x0004_0002));
+        capture.extend([0; 8]);
+        capture.extend(to_bytes(65535));
+        capture.extend(to_bytes(link_type));
+        for frame in frames {
+            capture.extend([0; 8]);
+            capture.extend(to_bytes(frame.len() as u32));
+            capture.extend(to_bytes(frame.len() as u32));
+            capture.extend(frame);
+        }
+        capture
+    }
+
+    /// A little-endian pcapng block
+    fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let padded = body.len().next_multiple_of(4);
+        let len = (12 + padded) as u32;
+        let mut block = block_type.to_le_bytes().to_vec();
+        block.extend(len.to_le_bytes());
+        block.extend(body);
+        block.resize(8 + padded, 0);
+        block.extend(len.to_le_bytes());
+        block
+    }
+
+    fn pcapng_section() -> Vec<u8> {
+        let mut body = 0x1a2b3c4d_u32.to_le_bytes().to_vec();
+        body.extend([1, 0, 0, 0]);
+        body.extend(u64::MAX.to_le_bytes());
+        pcapng_block(PCAPNG_SECTION_HEADER, &body)
+    }
+
+    fn pcapng_interface(link_type: u16) -> Vec<u8> {
+        let mut body = link_type.to_le_bytes().to_vec();
+        body.extend([0, 0, 0, 0, 0, 0]);
+        pcapng_block(PCAPNG_INTERFACE_DESCRIPTION, &body)
+    }
+
+    fn pcapng_enhanced_packet(interface: u32, frame: &[u8]) -> Vec<u8> {
+        let mut body = interface.to_le_bytes().to_vec();
+        body.extend([0; 8]);
+        body.extend((frame.len() as u32).to_le_bytes());
+        body.extend((frame.len() as u32).to_le_bytes());
+        body.extend(frame);
+        pcapng_block(PCAPNG_ENHANCED_PACKET, &body)
+    }
+
+    fn pcapng_simple_packet(frame: &[u8]) -> Vec<u8> {
+        let mut body = (frame.len() as u32).to_le_bytes().to_vec();
+        body.extend(frame);
+        pcapng_block(PCAPNG_SIMPLE_PACKET, &body)
+    }
+
+    #[test]
+    fn reads_pcap_in_either_byte_order() {
+        let frames = [udp_packet(53, b"query"), tcp_packet(80, b"GET / HTTP/1.0\r\n\r\n")];
+        for to_bytes in [u32::to_le_bytes, u32::to_be_bytes] {
+            let capture = pcap(LINKTYPE_RAW, &frames, to_bytes);
+            assert_eq!(payloads(&capture, None).unwrap(), [&b"query"[..], b"GET / HTTP/1.0\r\n\r\n"]);
+        }
+    }
+
+    #[test]
+    fn skips_packets_without_payloads() {
+        let capture = pcap(LINKTYPE_ETHERNET, &[ethernet(&tcp_packet(80, b"")), ethernet(&udp_packet(80, b"hi"))], u32::to_le_bytes);
+        assert_eq!(payloads(&capture, None).unwrap(), [b"hi"]);
+    }
+
+    #[test]
+    fn keeps_only_packets_sent_to_the_port() {
+        let frames = [udp_packet(53, b"request"), udp_packet(12345, b"response"), udp_packet(53, b"again")];
+        let capture = pcap(LINKTYPE_RAW, &frames, u32::to_le_bytes);
+        assert_eq!(payloads(&capture, Some(53)).unwrap(), [&b"request"[..], b"again"]);
+        assert_eq!(payloads(&capture, Some(12345)).unwrap(), [b"response"]);
+        assert!(payloads(&capture, Some(80)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_pcapng_packet_blocks() {
+        let mut capture = pcapng_section();
+        capture.extend(pcapng_interface(LINKTYPE_ETHERNET as u16));
+        capture.extend(pcapng_block(5, &[0; 8]));
+        capture.extend(pcapng_enhanced_packet(0, &ethernet(&udp_packet(53, b"odd"))));
+        capture.extend(pcapng_simple_packet(&ethernet(&tcp_packet(53, b"four"))));
+
+        assert_eq!(payloads(&capture, None).unwrap(), [&b"odd"[..], b"four"]);
+    }
+
+    #[test]
+    fn rejects_packets_on_unknown_interfaces() {
+        let mut capture = pcapng_section();
+        capture.extend(pcapng_enhanced_packet(0, &udp_packet(53, b"lost")));
+        assert!(payloads(&capture, None).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_captures() {
+        let capture = pcap(LINKTYPE_RAW, &[udp_packet(53, b"query")], u32::to_le_bytes);
+        for len in [0, 3, 23, 24 + 15, capture.len() - 1] {
+            assert!(payloads(&capture[..len], None).is_err(), "{} bytes", len);
+        }
+        assert!(payloads(&capture[..24], None).unwrap().is_empty());
+
+        let mut capture = pcapng_section();
+        capture.extend(pcapng_interface(LINKTYPE_RAW as u16));
+        capture.extend(pcapng_enhanced_packet(0, &udp_packet(53, b"query")));
+        for len in [11, capture.len() - 1] {
+            assert!(payloads(&capture[..len], None).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(payloads(b"GET / HTTP/1.0\r\n\r\n", None).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+//! The preload library, embedded in the binary when it's built (see `build.rs`) so that the two
+//! always agree on the protocol, and written out for targets to load when a run starts.
+
+use std::ffi::{CString, OsString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+static LIBRARY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libquikcov_preload.so"));
+
+/// A copy of the embedded preload library in a directory of its own that only we can access,
+/// removed (along with the directory) when dropped.
+pub struct ExtractedPreload {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl ExtractedPreload {
+    /// Writes the library out below the system's temporary directory.
+    pub fn extract() -> io::Result<Self> {
+        let dir = make_temp_dir()?;
+        let path = dir.join("libquikcov_preload.so");
+        let extracted = Self { dir, path };
+
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(0o500).open(&extracted.path)?;
+        file.write_all(LIBRARY)?;
+
+        log::debug!("extracted the preload library to {}", extracted.path.display());
+        Ok(extracted)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Creates a directory with a fresh, unpredictable name below the system's temporary directory,
+/// which only we can access (so that nobody else can swap the library out).
+fn make_temp_dir() -> io::Result<PathBuf> {
+    let template = std::env::temp_dir().join("quikcov-XXXXXX").into_os_string().into_vec();
+    let mut template = CString::new(template).map_err(io::Error::other)?.into_bytes_with_nul();
+
+    if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+        return Err(io::Error::last_os_error())
+    }
+
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+impl Drop for ExtractedPreload {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            log::warn!("failed to remove the extracted preload library {}: {}", self.dir.display(), e);
+        }
+    }
+}
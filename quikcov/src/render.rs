@@ -0,0 +1,210 @@
+//! Renders result sets for people and other tools to read.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::Path;
+
+use quikcov_common::prelude::*;
+
+use crate::results::ResultSet;
+
+/// Returns the execution count of each line of `file`, in order. Lines claimed by several functions
+/// (e.g. instances of an inline function) take the highest count.
+fn line_counts(file: &FileCoverage) -> BTreeMap<u32, u64> {
+    let mut lines = BTreeMap::new();
+    for line in file.fns.values().flat_map(|function| &function.lines) {
+        let count = lines.entry(line.lineno).or_insert(0);
+        *count = (*count).max(line.exec_count);
+    }
+    lines
+}
+
+/// Returns the functions of `file` in the order they appear in the source.
+fn functions(file: &FileCoverage) -> Vec<(&String, &FnCoverage)> {
+    let mut functions: Vec<_> = file.fns.iter().collect();
+    functions.sort_by_key(|(name, function)| (function.start_line, *name));
+    functions
+}
+
+/// The number of times `function` was called, which is how often its entry block ran
+fn calls(function: &FnCoverage) -> u64 {
+    function.blocks.first().map_or(0, |block| block.executions)
+}
+
+fn percent(covered: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        (covered * 100) as f64 / total as f64
+    }
+}
+
+/// Block totals of a set of functions
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct Blocks {
+    covered: usize,
+    total: usize,
+}
+
+impl Blocks {
+    fn of<'a>(functions: impl IntoIterator<Item = &'a FnCoverage>) -> Self {
+        functions.into_iter().fold(Blocks::default(), |blocks, function| Blocks {
+            covered: blocks.covered + function.executed_blocks,
+            total: blocks.total + function.total_blocks,
+        })
+    }
+}
+
+impl std::fmt::Display for Blocks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{} ({:.2}%)", self.covered, self.total, percent(self.covered, self.total))
+    }
+}
+
+/// Renders `coverage` as an LCOV tracefile, as read by `genhtml` and most coverage services.
+pub fn lcov(coverage: &ProgCoverage) -> String {
+    let mut out = String::new();
+    let mut files: Vec<_> = coverage.files.iter().collect();
+    files.sort_by_key(|(name, _)| *name);
+
+    for (name, file) in files {
+        writeln!(out, "SF:{}", name).unwrap();
+
+        let functions = functions(file);
+        for (name, function) in &functions {
+            writeln!(out, "FN:{},{}", function.start_line, name).unwrap();
+        }
+        for (name, function) in &functions {
+            writeln!(out, "FNDA:{},{}", calls(function), name).unwrap();
+        }
+        writeln!(out, "FNF:{}", functions.len()).unwrap();
+        writeln!(out, "FNH:{}", functions.iter().filter(|(_, function)| function.executed_blocks > 0).count()).unwrap();
+
+        let lines = line_counts(file);
+        for (lineno, count) in &lines {
+            writeln!(out, "DA:{},{}", lineno, count).unwrap();
+        }
+        writeln!(out, "LF:{}", lines.len()).unwrap();
+        writeln!(out, "LH:{}", lines.values().filter(|&&count| count > 0).count()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+    }
+
+    out
+}
+
+/// Renders a table of the block and line coverage of every file and component in `results`.
+pub fn summary(results: &ResultSet) -> String {
+    let mut out = String::new();
+
+    for (component, coverage) in &results.components {
+        writeln!(out, "{}", component).unwrap();
+
+        let mut files: Vec<_> = coverage.files.iter().collect();
+        files.sort_by_key(|(name, _)| *name);
+        for (name, file) in files {
+            let lines = line_counts(file);
+            let hit = lines.values().filter(|&&count| count > 0).count();
+            writeln!(out, "  {:<60} blocks {:<20} lines {}/{}", name, Blocks::of(file.fns.values()), hit, lines.len()).unwrap();
+        }
+
+        let blocks = Blocks::of(coverage.files.values().flat_map(|file| file.fns.values()));
+        writeln!(out, "  {:<60} blocks {}", "total", blocks).unwrap();
+    }
+
+    out
+}
+
+/// Renders how the coverage went from `base` to `head`: the totals of each component, then every
+/// function whose executed blocks changed.
+pub fn diff(base: ResultSet, head: ResultSet) -> String {
+    let mut out = String::new();
+
+    let component_names: BTreeSet<_> = base.components.keys().chain(head.components.keys()).cloned().collect();
+    let totals = |results: &ResultSet, name: &String| {
+        results.components.get(name).map_or(Blocks::default(), |coverage| Blocks::of(coverage.files.values().flat_map(|file| file.fns.values())))
+    };
+    for name in &component_names {
+        let (before, after) = (totals(&base, name), totals(&head, name));
+        writeln!(out, "{:<40} {} -> {} ({:+} blocks)", name, before, after, after.covered as i64 - before.covered as i64).unwrap();
+    }
+
+    let (base, head) = (base.combined(), head.combined());
+    let blocks_of = |coverage: &ProgCoverage| -> BTreeMap<(String, String), Blocks> {
+        coverage.files.iter()
+            .flat_map(|(file_name, file)| file.fns.iter().map(move |(fn_name, function)| ((file_name.clone(), fn_name.clone()), Blocks::of([function]))))
+            .collect()
+    };
+    let (before, after) = (blocks_of(&base), blocks_of(&head));
+
+    let mut keys: Vec<_> = before.keys().chain(after.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let (was, is) = (before.get(key).copied().unwrap_or_default(), after.get(key).copied().unwrap_or_default());
+        if was.covered == is.covered {
+            continue
+        }
+        let sign = if is.covered > was.covered { '+' } else { '-' };
+        writeln!(out, "{} {}:{} {} -> {}", sign, key.0, key.1, was, is).unwrap();
+    }
+
+    out
+}
+
+/// Renders the coverage of the source file `file_name` (or, if given, of just its function
+/// `function_name`) line by line, alongside the source if it can be read. Files may be named by
+/// any suffix of their path, and relative ones are looked for in each of `source_dirs`; returns
+/// `None` if nothing matches.
+pub fn show(coverage: &ProgCoverage, source_dirs: &[String], file_name: &str, function_name: Option<&str>) -> Option<String> {
+    let (name, file) = coverage.files.iter()
+        .filter(|(name, _)| *name == file_name || Path::new(name).ends_with(file_name))
+        .min_by_key(|(name, _)| name.len())?;
+
+    let mut out = String::new();
+    let lines = match function_name {
+        Some(function_name) => {
+            let function = file.fns.get(function_name)?;
+            writeln!(out, "{}:{}: called {} times, blocks {}", name, function_name, calls(function), Blocks::of([function])).unwrap();
+            function.lines.iter().map(|line| (line.lineno, line.exec_count)).collect()
+        }
+        None => {
+            writeln!(out, "{}: blocks {}", name, Blocks::of(file.fns.values())).unwrap();
+            for (function_name, function) in functions(file) {
+                writeln!(out, "  {:>6}: {} (called {} times, blocks {})", function.start_line, function_name, calls(function), Blocks::of([function])).unwrap();
+            }
+            line_counts(file)
+        }
+    };
+
+    // Like gcov: lines without code are marked `-`, and those that never ran `#####`
+    let source = std::iter::once(Path::new(name).to_path_buf())
+        .chain(source_dirs.iter().map(|dir| Path::new(dir).join(name)))
+        .find_map(|path| std::fs::read_to_string(path).ok());
+    match source {
+        Some(source) => {
+            // A function is shown from its first line to its last
+            let range = lines.keys().next().copied().zip(lines.keys().next_back().copied());
+            for (lineno, text) in (1..).zip(source.lines()) {
+                if let (Some(_), Some((first, last))) = (function_name, range) {
+                    if !(first..=last).contains(&lineno) {
+                        continue
+                    }
+                }
+                let count = match lines.get(&lineno) {
+                    Some(0) => "#####".to_string(),
+                    Some(count) => count.to_string(),
+                    None => "-".to_string(),
+                };
+                writeln!(out, "{:>9}:{:>5}:{}", count, lineno, text).unwrap();
+            }
+        }
+        None => {
+            for (lineno, count) in &lines {
+                let count = if *count == 0 { "#####".to_string() } else { count.to_string() };
+                writeln!(out, "{:>9}:{:>5}", count, lineno).unwrap();
+            }
+        }
+    }
+
+    Some(out)
+}
@@ -0,0 +1,215 @@
+//! Runs seeds on several executors at once, while handing their results back in seed order so that
+//! they fold into the cumulative coverage exactly as a sequential run would.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+
+use quikcov_common::protocol::Message;
+
+use crate::executor::{Executor, Run};
+
+/// How many seeds each executor may be ahead of the oldest seed not yet handed on, bounding how
+/// many seeds' messages are held at once
+const SEEDS_AHEAD_PER_JOB: usize = 2;
+
+/// What is handed on for a seed: each of its messages, then how its run ended.
+#[derive(Debug)]
+pub enum Event {
+    Message(Message),
+    Finished(Run),
+}
+
+/// Runs each of `seeds` on one of `jobs` executors, the `n`th of which is made by
+/// `new_executor(n)`, and passes each seed's position in `seeds` along with its messages and run
+/// to `on_event`, seed after seed.
+///
+/// A single executor runs on the calling thread and hands messages on as they come. Several run on
+/// threads of their own, and the messages of every seed are held until those of all the seeds
+/// before it have been handed on; executors wait rather than get too far ahead of the oldest seed
+/// still running.
+pub fn replay(
+    seeds: &[PathBuf],
+    jobs: usize,
+    new_executor: &(dyn Fn(usize) -> Box<dyn Executor> + Sync),
+    on_event: &mut dyn FnMut(usize, Event),
+) {
+    if jobs <= 1 {
+        let mut executor = new_executor(0);
+        for (i, path) in seeds.iter().enumerate() {
+            log::info!("Testing seed file \"{}\"", path.display());
+            let run = executor.run(path, &mut |message| on_event(i, Event::Message(message)));
+            on_event(i, Event::Finished(run));
+        }
+        return
+    }
+
+    let next_seed = AtomicUsize::new(0);
+    let window = Window::new(jobs * SEEDS_AHEAD_PER_JOB);
+    let (results, finished) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        for worker in 0..jobs {
+            let results = results.clone();
+            let next_seed = &next_seed;
+            let window = &window;
+            scope.spawn(move || {
+                let mut executor = new_executor(worker);
+                loop {
+                    let i = next_seed.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = seeds.get(i) else {
+                        break
+                    };
+                    if !window.wait_for(i) {
+                        break
+                    }
+
+                    log::info!("Testing seed file \"{}\"", path.display());
+                    let mut messages = Vec::new();
+                    let run = executor.run(path, &mut |message| messages.push(message));
+
+                    if results.send((i, run, messages)).is_err() {
+                        break
+                    }
+                }
+            });
+        }
+        drop(results);
+
+        // Lets the workers go if we stop handing seeds on early (i.e. `on_event` panics)
+        let _close = CloseOnDrop(&window);
+
+        // Seeds that finished ahead of one still running wait here for their turn
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (i, run, messages) in finished {
+            pending.insert(i, (run, messages));
+            while let Some((run, messages)) = pending.remove(&next) {
+                for message in messages {
+                    on_event(next, Event::Message(message));
+                }
+                on_event(next, Event::Finished(run));
+                next += 1;
+                window.advance(next);
+            }
+        }
+    });
+}
+
+/// The seeds that may be run: those less than `size` past the oldest seed not yet handed on.
+struct Window {
+    size: usize,
+    /// The oldest seed not yet handed on, or `None` once no more will be
+    oldest: Mutex<Option<usize>>,
+    moved: Condvar,
+}
+
+impl Window {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            oldest: Mutex::new(Some(0)),
+            moved: Condvar::new(),
+        }
+    }
+
+    /// Waits until seed `i` may be run, returning `false` if no more seeds will be handed on.
+    fn wait_for(&self, i: usize) -> bool {
+        let oldest = self.moved.wait_while(self.oldest.lock().unwrap(), |oldest| oldest.is_some_and(|oldest| i >= oldest + self.size)).unwrap();
+        oldest.is_some()
+    }
+
+    fn advance(&self, oldest: usize) {
+        *self.oldest.lock().unwrap() = Some(oldest);
+        self.moved.notify_all();
+    }
+
+    fn close(&self) {
+        // Poisoning doesn't matter here, as the value is overwritten anyway
+        *self.oldest.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.moved.notify_all();
+    }
+}
+
+struct CloseOnDrop<'a>(&'a Window);
+
+impl Drop for CloseOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use quikcov_common::protocol::Body;
+
+    use super::*;
+    use crate::executor::Outcome;
+
+    /// Runs seeds named after their number, sending one message per seed. Later seeds finish
+    /// sooner, so that several executors finish them out of order.
+    struct FakeExecutor {
+        /// How many seeds have been started, and how many have been handed on
+        started: Arc<AtomicUsize>,
+        handed_on: Arc<AtomicUsize>,
+        /// The most seeds that were ever started but not handed on
+        most_ahead: Arc<AtomicUsize>,
+    }
+
+    impl Executor for FakeExecutor {
+        fn run(&mut self, seed: &Path, on_message: &mut dyn FnMut(Message)) -> Run {
+            let n: u32 = seed.to_str().unwrap().parse().unwrap();
+            let started = self.started.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_ahead.fetch_max(started - self.handed_on.load(Ordering::SeqCst), Ordering::SeqCst);
+
+            std::thread::sleep(Duration::from_millis(u64::from(8 - n % 8)));
+            on_message(Message { pid: n, ppid: 0, body: Body::DumpComplete { seed: None } });
+
+            Run {
+                outcome: Outcome::Ok,
+                runtime: Duration::ZERO,
+                max_rss: Some(u64::from(n)),
+                stderr: None,
+            }
+        }
+    }
+
+    fn replay_in_order(jobs: usize) -> usize {
+        let seeds: Vec<PathBuf> = (0..40).map(|n| PathBuf::from(n.to_string())).collect();
+        let started = Arc::new(AtomicUsize::new(0));
+        let handed_on = Arc::new(AtomicUsize::new(0));
+        let most_ahead = Arc::new(AtomicUsize::new(0));
+
+        let new_executor = |_| -> Box<dyn Executor> {
+            Box::new(FakeExecutor { started: started.clone(), handed_on: handed_on.clone(), most_ahead: most_ahead.clone() })
+        };
+
+        let mut events = Vec::new();
+        replay(&seeds, jobs, &new_executor, &mut |i, event| {
+            let event = match event {
+                Event::Message(message) => (message.pid as usize, false),
+                Event::Finished(run) => {
+                    handed_on.fetch_add(1, Ordering::SeqCst);
+                    (run.max_rss.unwrap() as usize, true)
+                }
+            };
+            events.push((i, event));
+        });
+
+        let expected: Vec<_> = (0..seeds.len()).flat_map(|i| [(i, (i, false)), (i, (i, true))]).collect();
+        assert_eq!(events, expected);
+
+        most_ahead.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn hands_seeds_on_in_order() {
+        assert_eq!(replay_in_order(1), 1);
+        assert!(replay_in_order(4) <= 4 * SEEDS_AHEAD_PER_JOB);
+    }
+}
@@ -0,0 +1,131 @@
+//! The results a run leaves in its output directory: the totals after each seed
+//! (`<idx>.coverage.json`), and the full coverage of every component once it's done
+//! ([`ResultSet`]), which is what the other subcommands work from.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use fxhash::FxBuildHasher;
+use quikcov_common::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The full coverage of each component of the program, as stored in the `coverage.json` of a
+/// results directory.
+#[derive(Deserialize, Serialize)]
+pub struct ResultSet {
+    pub components: BTreeMap<String, ProgCoverage>,
+}
+
+impl ResultSet {
+    /// The name of the file a result set is stored in, within its directory
+    pub const FILE_NAME: &'static str = "coverage.json";
+
+    /// Reads the result set stored in the directory `dir`.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(Self::FILE_NAME);
+        let json = fs::read(&path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        serde_json::from_slice(&json).map_err(|e| format!("failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Stores the result set in the directory `dir`, creating it if need be.
+    pub fn save(&self, dir: &Path) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(Self::FILE_NAME), serde_json::to_vec(self).unwrap()).unwrap();
+    }
+
+    /// Adds the coverage of `other` to ours, component by component.
+    pub fn merge(&mut self, other: ResultSet) {
+        for (name, coverage) in other.components {
+            match self.components.get_mut(&name) {
+                Some(ours) => ours.merge(coverage).unwrap(),
+                None => _ = self.components.insert(name, coverage),
+            }
+        }
+    }
+
+    /// Returns the coverage of the whole program, merging that of the source files (e.g. headers)
+    /// several components share.
+    pub fn combined(self) -> ProgCoverage {
+        self.components.into_values().reduce(|mut a, b| { a.merge(b).unwrap(); a }).expect("no coverage in result set")
+    }
+}
+
+/// The coverage contributed by a single process spawned during a seed's execution, over the
+/// `.gcda` files it wrote
+#[derive(Deserialize, Serialize)]
+pub struct CoverageProcess {
+    pub pid: u32,
+    pub ppid: u32,
+    pub coverage: CoverageOne,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CoverageOne {
+    pub covered_blocks: usize,
+    pub total_blocks: usize,
+    /// The totals of each component, keyed by name
+    #[serde(default)]
+    pub components: BTreeMap<String, CoverageComponent>,
+    pub files: HashMap<String, CoverageFile, FxBuildHasher>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CoverageComponent {
+    pub covered_blocks: usize,
+    pub total_blocks: usize,
+}
+
+impl CoverageOne {
+    pub fn new(results: ResultSet) -> Self {
+        let mut components = BTreeMap::new();
+        for (name, cov) in &results.components {
+            let functions = cov.files.values().flat_map(|file| file.fns.values());
+            components.insert(name.clone(), CoverageComponent {
+                covered_blocks: functions.clone().map(|function| function.executed_blocks).sum(),
+                total_blocks: functions.map(|function| function.total_blocks).sum(),
+            });
+        }
+
+        let cov = results.combined();
+
+        let mut covered_blocks = 0;
+        let mut total_blocks = 0;
+        let mut files = HashMap::with_hasher(FxBuildHasher::default());
+        for (name, file) in cov.files {
+            let cov_file = CoverageFile::new(file);
+            covered_blocks += cov_file.covered_blocks;
+            total_blocks += cov_file.total_blocks;
+            files.insert(name, cov_file);
+        }
+
+        Self {
+            covered_blocks,
+            total_blocks,
+            components,
+            files,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CoverageFile {
+    pub covered_blocks: usize,
+    pub total_blocks: usize,
+}
+
+impl CoverageFile {
+    pub fn new(cov: FileCoverage) -> Self {
+        let mut covered_blocks = 0;
+        let mut total_blocks = 0;
+        for (_fn_name, function) in cov.fns {
+            covered_blocks += function.executed_blocks;
+            total_blocks += function.total_blocks;
+        }
+
+        Self {
+            covered_blocks,
+            total_blocks,
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Picks the first sanitizer report (ASan, MSan, UBSan and the like) out of a target's stderr, so
+//! that crashes can be told apart without replaying them.
+
+use serde::Serialize;
+
+/// How many of the innermost frames of a report's stack are kept
+const TOP_FRAMES: usize = 5;
+
+/// The gist of a sanitizer report.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Report {
+    /// The sanitizer that reported the error, e.g. `AddressSanitizer`
+    pub sanitizer: String,
+    /// The kind of error, e.g. `heap-buffer-overflow`
+    pub crash_type: String,
+    /// The innermost frames of the stack the error was reported on, as `function file:line` (or
+    /// `(module+offset)` without symbols)
+    pub frames: Vec<String>,
+}
+
+/// Returns the first sanitizer report in `stderr`, if there is one.
+pub fn parse(stderr: &str) -> Option<Report> {
+    let mut lines = stderr.lines();
+
+    let mut report = lines.by_ref().find_map(|line| header(line).or_else(|| runtime_error(line)))?;
+    let location = report.frames.pop();
+
+    for line in lines {
+        match frame(line) {
+            // A new stack starts over at #0, e.g. to show where the memory was allocated
+            Some((0, _)) if !report.frames.is_empty() => break,
+            Some((_, frame)) if report.frames.len() < TOP_FRAMES => report.frames.push(frame.to_string()),
+            Some(_) => {}
+            None if !report.frames.is_empty() => break,
+            None => {}
+        }
+    }
+
+    // UBSan only prints a stack when asked to, but always gives the location of the error
+    if report.frames.is_empty() {
+        report.frames.extend(location);
+    }
+
+    Some(report)
+}
+
+/// Parses the first line of a report, like
+/// `==1234==ERROR: AddressSanitizer: heap-buffer-overflow on address 0x602000000011 at pc ...`.
+fn header(line: &str) -> Option<Report> {
+    let (prefix, description) = line.split_once("Sanitizer: ")?;
+    let (prefix, name) = prefix.rsplit_once(' ')?;
+    if !(prefix.ends_with("ERROR:") || prefix.ends_with("WARNING:")) || name.contains(char::is_whitespace) {
+        return None
+    }
+
+    Some(Report {
+        sanitizer: format!("{}Sanitizer", name),
+        crash_type: crash_type(description),
+        frames: Vec::new(),
+    })
+}
+
+/// Parses UBSan's one-line reports, like
+/// `parse.c:12:5: runtime error: signed integer overflow: 2147483647 + 1 cannot be represented...`.
+fn runtime_error(line: &str) -> Option<Report> {
+    let (location, description) = line.split_once(": runtime error: ")?;
+
+    Some(Report {
+        sanitizer: "UndefinedBehaviorSanitizer".to_string(),
+        crash_type: crash_type(description),
+        // Set aside by `parse()` until it knows whether a stack follows
+        frames: vec![location.trim().to_string()],
+    })
+}
+
+/// Cuts the details (addresses, values, thread ids) off the description of an error.
+fn crash_type(description: &str) -> String {
+    let end = [" on ", " (", ":"].iter()
+        .filter_map(|separator| description.find(separator))
+        .min()
+        .unwrap_or(description.len());
+    description[..end].trim().to_string()
+}
+
+/// Parses a line of a stack trace, like `    #0 0x4f3b2c in main /src/parse.c:12:5`, returning
+/// the number of the frame and what follows its address.
+fn frame(line: &str) -> Option<(usize, &str)> {
+    let (number, rest) = line.trim_start().strip_prefix('#')?.split_once(' ')?;
+    let number = number.parse().ok()?;
+    let (_address, location) = rest.trim_start().split_once(' ')?;
+    // Newer runtimes tack the module's build id onto frames without symbols
+    let location = location.split(" (BuildId: ").next().unwrap().trim();
+    Some((number, location.strip_prefix("in ").unwrap_or(location)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_asan_reports() {
+        let stderr = "\
+parsing input...
+=================================================================
+==1234==ERROR: AddressSanitizer: heap-buffer-overflow on address 0x602000000011 at pc 0x4f3b2d bp 0x7ffd sp 0x7ffc
+READ of size 1 at 0x602000000011 thread T0
+    #0 0x4f3b2c in parse_header /src/parse.c:12:5
+    #1 0x4f3c10 in parse /src/parse.c:40:9
+    #2 0x4f3d00 in main /src/main.c:8:3
+    #3 0x7f12 in __libc_start_main (/lib/x86_64-linux-gnu/libc.so.6+0x21c86) (BuildId: 0123abcd)
+
+0x602000000011 is located 0 bytes to the right of 1-byte region [0x602000000010,0x602000000011)
+allocated by thread T0 here:
+    #0 0x4bc3f2 in malloc (/src/parser+0x4bc3f2)
+    #1 0x4f3a00 in read_input /src/io.c:3:10
+";
+        assert_eq!(parse(stderr), Some(Report {
+            sanitizer: "AddressSanitizer".to_string(),
+            crash_type: "heap-buffer-overflow".to_string(),
+            frames: vec![
+                "parse_header /src/parse.c:12:5".to_string(),
+                "parse /src/parse.c:40:9".to_string(),
+                "main /src/main.c:8:3".to_string(),
+                "__libc_start_main (/lib/x86_64-linux-gnu/libc.so.6+0x21c86)".to_string(),
+            ],
+        }));
+    }
+
+    #[test]
+    fn stops_at_the_next_stack() {
+        // Without a blank line between them, the allocation stack still starts over at #0
+        let stderr = "\
+==1==ERROR: AddressSanitizer: heap-use-after-free on address 0x6 at pc 0x1 bp 0x2 sp 0x3
+    #0 0x1 in use /src/a.c:1:1
+freed by thread T0 here:
+    #0 0x2 in free (/src/a+0x2)
+";
+        assert_eq!(parse(stderr).unwrap().frames, ["use /src/a.c:1:1"]);
+
+        let stderr = "\
+==1==ERROR: AddressSanitizer: stack-overflow on address 0x7ffe at pc 0x1 bp 0x2 sp 0x3 T0
+    #0 0x1 in recurse /src/a.c:1:1
+    #0 0x2 in malloc (/src/a+0x2)
+";
+        assert_eq!(parse(stderr).unwrap().frames, ["recurse /src/a.c:1:1"]);
+    }
+
+    #[test]
+    fn keeps_the_innermost_frames() {
+        let mut stderr = "==1==WARNING: MemorySanitizer: use-of-uninitialized-value\n".to_string();
+        for i in 0..8 {
+            stderr += &format!("    #{} 0x{:x} in f{} /src/a.c:{}:1\n", i, i, i, i);
+        }
+
+        let report = parse(&stderr).unwrap();
+        assert_eq!(report.sanitizer, "MemorySanitizer");
+        assert_eq!(report.crash_type, "use-of-uninitialized-value");
+        assert_eq!(report.frames.len(), TOP_FRAMES);
+        assert_eq!(report.frames[TOP_FRAMES - 1], "f4 /src/a.c:4:1");
+    }
+
+    #[test]
+    fn parses_ubsan_runtime_errors() {
+        let stderr = "parse.c:12:5: runtime error: signed integer overflow: 2147483647 + 1 cannot be represented in type 'int'\n";
+        assert_eq!(parse(stderr), Some(Report {
+            sanitizer: "UndefinedBehaviorSanitizer".to_string(),
+            crash_type: "signed integer overflow".to_string(),
+            frames: vec!["parse.c:12:5".to_string()],
+        }));
+
+        // With `print_stacktrace=1`, the stack takes the place of the location
+        let stderr = "\
+parse.c:12:5: runtime error: load of misaligned address 0x01 for type 'int'
+    #0 0x4f3b2c in parse /src/parse.c:12:5
+";
+        assert_eq!(parse(stderr).unwrap().frames, ["parse /src/parse.c:12:5"]);
+    }
+
+    #[test]
+    fn ignores_other_output() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("warning: AddressSanitizer: not a report\n#0 is not a frame\n"), None);
+        assert_eq!(parse("==1==ERROR: Leak Sanitizer: with a space\n"), None);
+    }
+}
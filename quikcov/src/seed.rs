@@ -0,0 +1,82 @@
+//! Splits seeds into the protocol messages they hold, so that stateful targets get each message
+//! in a packet (or read) of its own.
+
+use crate::pcap;
+
+/// How the messages of a seed are laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SeedFormat {
+    /// The whole seed is a single message
+    #[default]
+    Raw,
+    /// AFLNet's replayable seeds: each message is preceded by its length, as a little-endian u32
+    Aflnet,
+    /// Each message is preceded by its length, as a big-endian u32
+    LengthPrefixed,
+    /// A pcap or pcapng capture, whose TCP, UDP and SCTP payloads are the messages
+    Pcap,
+}
+
+impl SeedFormat {
+    /// Splits `seed` into its messages. Captures only yield the packets sent to `port`, if given.
+    pub fn decode(self, seed: &[u8], port: Option<u16>) -> Result<Vec<&[u8]>, String> {
+        match self {
+            SeedFormat::Raw => Ok(vec![seed]),
+            SeedFormat::Aflnet => length_prefixed(seed, u32::from_le_bytes),
+            SeedFormat::LengthPrefixed => length_prefixed(seed, u32::from_be_bytes),
+            SeedFormat::Pcap => pcap::payloads(seed, port),
+        }
+    }
+}
+
+fn length_prefixed(mut seed: &[u8], read_len: fn([u8; 4]) -> u32) -> Result<Vec<&[u8]>, String> {
+    let mut messages = Vec::new();
+
+    while !seed.is_empty() {
+        let Some((len, rest)) = seed.split_first_chunk::<4>() else {
+            return Err(format!("{} trailing bytes after message {} are too short for a length", seed.len(), messages.len()))
+        };
+        let len = read_len(*len) as usize;
+        if len > rest.len() {
+            return Err(format!("message {} is {} bytes long but only {} bytes remain", messages.len(), len, rest.len()))
+        }
+
+        let (message, rest) = rest.split_at(len);
+        messages.push(message);
+        seed = rest;
+    }
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_raw_seeds_whole() {
+        assert_eq!(SeedFormat::Raw.decode(b"\x00\x00\x00\x01a", None).unwrap(), [b"\x00\x00\x00\x01a"]);
+    }
+
+    #[test]
+    fn splits_aflnet_seeds() {
+        let seed = b"\x04\x00\x00\x00USER\x00\x00\x00\x00\x02\x00\x00\x00\r\n";
+        assert_eq!(SeedFormat::Aflnet.decode(seed, None).unwrap(), [&b"USER"[..], b"", b"\r\n"]);
+    }
+
+    #[test]
+    fn splits_length_prefixed_seeds() {
+        let seed = b"\x00\x00\x00\x04USER\x00\x00\x00\x02\r\n";
+        assert_eq!(SeedFormat::LengthPrefixed.decode(seed, None).unwrap(), [&b"USER"[..], b"\r\n"]);
+        // Read in the other byte order, the first length is far too long
+        assert!(SeedFormat::Aflnet.decode(seed, None).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_seeds() {
+        // A partial length, then a message shorter than its length
+        assert!(SeedFormat::Aflnet.decode(b"\x04\x00\x00\x00USER\x02\x00", None).is_err());
+        assert!(SeedFormat::Aflnet.decode(b"\x04\x00\x00\x00USE", None).is_err());
+        assert!(SeedFormat::Aflnet.decode(b"", None).unwrap().is_empty());
+    }
+}
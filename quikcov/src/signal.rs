@@ -0,0 +1,76 @@
+//! Signals as named on the command line.
+
+use std::fmt;
+use std::str::FromStr;
+
+const NAMES: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ABRT", libc::SIGABRT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+];
+
+/// A signal, given by number or by name (with or without the `SIG` prefix, e.g. `TERM`, `SIGUSR1`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signal(pub libc::c_int);
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(number) = s.parse() {
+            return if (1..=libc::SIGRTMAX()).contains(&number) {
+                Ok(Signal(number))
+            } else {
+                Err(format!("no signal numbered {}", number))
+            }
+        }
+
+        let name = s.get(..3).filter(|prefix| prefix.eq_ignore_ascii_case("SIG")).map_or(s, |_| &s[3..]);
+        NAMES.iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|&(_, number)| Signal(number))
+            .ok_or_else(|| format!("unknown signal `{}`", s))
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match NAMES.iter().find(|&&(_, number)| number == self.0) {
+            Some((name, _)) => write!(f, "SIG{}", name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_and_numbers() {
+        assert_eq!("TERM".parse(), Ok(Signal(libc::SIGTERM)));
+        assert_eq!("SIGUSR1".parse(), Ok(Signal(libc::SIGUSR1)));
+        assert_eq!("sigkill".parse(), Ok(Signal(libc::SIGKILL)));
+        assert_eq!("9".parse(), Ok(Signal(9)));
+
+        assert!("SIGFOO".parse::<Signal>().is_err());
+        assert!("SIG".parse::<Signal>().is_err());
+        assert!("0".parse::<Signal>().is_err());
+        assert!("-15".parse::<Signal>().is_err());
+        assert!("1000".parse::<Signal>().is_err());
+    }
+
+    #[test]
+    fn displays_names_where_known() {
+        assert_eq!(Signal(libc::SIGALRM).to_string(), "SIGALRM");
+        assert_eq!(Signal(libc::SIGRTMIN()).to_string(), libc::SIGRTMIN().to_string());
+    }
+}
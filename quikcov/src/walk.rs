@@ -0,0 +1,254 @@
+//! Finds the `.gcno` and `.gcda` files under a coverage directory.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use walkdir::WalkDir;
+
+/// A directory holding the `.gcno` files of a component of the target (the program itself, a
+/// library it loads, a helper it runs), written as `[<name>=]<path>`. The path needn't be valid
+/// UTF-8, but the name must.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CoverageRoot {
+    /// The component's name, which defaults to the path
+    pub component: String,
+    pub path: PathBuf,
+}
+
+impl TryFrom<OsString> for CoverageRoot {
+    type Error = String;
+
+    fn try_from(s: OsString) -> Result<Self, Self::Error> {
+        // A path may contain `=` too, but a name never contains `/`
+        let bytes = s.as_bytes();
+        let (component, path) = match bytes.iter().position(|&byte| byte == b'=') {
+            Some(i) => match std::str::from_utf8(&bytes[..i]) {
+                Ok(component) if !component.contains('/') => (Some(component), OsStr::from_bytes(&bytes[i + 1..])),
+                _ => (None, s.as_os_str()),
+            },
+            None => (None, s.as_os_str()),
+        };
+        if component == Some("") || path.is_empty() {
+            return Err(format!("invalid coverage directory `{}` (expected `[<name>=]<path>`)", s.to_string_lossy()))
+        }
+
+        Ok(Self {
+            // Components are named in the results, which are UTF-8
+            component: component.map_or_else(|| path.to_string_lossy().into_owned(), str::to_string),
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl FromStr for CoverageRoot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OsString::from(s).try_into()
+    }
+}
+
+impl TryFrom<String> for CoverageRoot {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Which paths under a coverage directory are considered, by globs matched against the path
+/// relative to the directory (e.g. `third_party/**`).
+#[derive(Clone, Debug)]
+pub struct Filter {
+    /// Only paths matching one of these are considered, if any are given
+    include: Option<GlobSet>,
+    /// Paths matching one of these are never considered, nor is anything below them
+    exclude: GlobSet,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, globset::Error> {
+        Ok(Self {
+            include: (!include.is_empty()).then(|| glob_set(include)).transpose()?,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    fn includes(&self, relative_path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|include| include.is_match(relative_path))
+    }
+
+    fn excludes(&self, relative_path: &Path) -> bool {
+        self.exclude.is_match(relative_path)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            include: None,
+            exclude: GlobSet::empty(),
+        }
+    }
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    builder.build()
+}
+
+/// The files found under a coverage directory
+#[derive(Debug, Default)]
+pub struct CoverageFiles {
+    pub gcno: Vec<PathBuf>,
+    pub gcda: Vec<PathBuf>,
+}
+
+/// Finds the `.gcno` and `.gcda` files below `root`, in the order of their paths. Only `.gcno` files
+/// have to be included by `filter`, but nothing it excludes is walked into. Symbolic links below
+/// `root` aren't followed (`root` itself may be one), so that nothing outside it is found (and
+/// later deleted) and nothing is found twice; whatever can't be walked is logged and skipped.
+pub fn coverage_files(root: &Path, filter: &Filter) -> CoverageFiles {
+    let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_path_buf();
+
+    let walker = WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !filter.excludes(&relative(entry.path())));
+
+    let mut files = CoverageFiles::default();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("failed to walk coverage directory: {}", e);
+                continue
+            }
+        };
+
+        if !entry.file_type().is_file() {
+            continue
+        }
+        match entry.path().extension() {
+            Some(ext) if ext == "gcno" && filter.includes(&relative(entry.path())) => files.gcno.push(entry.into_path()),
+            Some(ext) if ext == "gcda" => files.gcda.push(entry.into_path()),
+            _ => {}
+        }
+    }
+
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+
+    /// `files`' paths relative to `root`
+    fn relative(root: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+        files.iter().map(|path| path.strip_prefix(root).unwrap().to_path_buf()).collect()
+    }
+
+    #[test]
+    fn parses_roots() {
+        assert_eq!("lib=build/lib".parse(), Ok(CoverageRoot { component: "lib".to_string(), path: PathBuf::from("build/lib") }));
+        assert_eq!("build".parse(), Ok(CoverageRoot { component: "build".to_string(), path: PathBuf::from("build") }));
+        // An `=` after a `/` is part of the path
+        assert_eq!("out/a=b".parse(), Ok(CoverageRoot { component: "out/a=b".to_string(), path: PathBuf::from("out/a=b") }));
+
+        assert!("=build".parse::<CoverageRoot>().is_err());
+        assert!("lib=".parse::<CoverageRoot>().is_err());
+        assert!("".parse::<CoverageRoot>().is_err());
+    }
+
+    #[test]
+    fn parses_roots_that_arent_utf8() {
+        let root = CoverageRoot::try_from(OsString::from(OsStr::from_bytes(b"lib=build/\xff"))).unwrap();
+        assert_eq!(root.component, "lib");
+        assert_eq!(root.path.as_os_str().as_bytes(), b"build/\xff");
+
+        let root = CoverageRoot::try_from(OsString::from(OsStr::from_bytes(b"build/\xff"))).unwrap();
+        assert_eq!(root.component, "build/\u{fffd}");
+        assert_eq!(root.path.as_os_str().as_bytes(), b"build/\xff");
+    }
+
+    #[test]
+    fn finds_files_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in ["b/y.gcno", "b/y.gcda", "a.gcno", "a.gcda", "a.o", "c/z.gcno"] {
+            touch(&dir.path().join(path));
+        }
+
+        let files = coverage_files(dir.path(), &Filter::default());
+        assert_eq!(relative(dir.path(), &files.gcno), ["a.gcno", "b/y.gcno", "c/z.gcno"].map(PathBuf::from));
+        assert_eq!(relative(dir.path(), &files.gcda), ["a.gcda", "b/y.gcda"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn filters_files_and_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in ["src/a.gcno", "src/a.gcda", "src/test/t.gcno", "third_party/zlib/z.gcno", "third_party/zlib/z.gcda"] {
+            touch(&dir.path().join(path));
+        }
+
+        let filter = Filter::new(&["src/**".to_string()], &["src/test".to_string()]).unwrap();
+        let files = coverage_files(dir.path(), &filter);
+        assert_eq!(relative(dir.path(), &files.gcno), [PathBuf::from("src/a.gcno")]);
+        // Only excluded directories hide .gcda files
+        assert_eq!(relative(dir.path(), &files.gcda), ["src/a.gcda", "third_party/zlib/z.gcda"].map(PathBuf::from));
+
+        let filter = Filter::new(&[], &["third_party/**".to_string()]).unwrap();
+        let files = coverage_files(dir.path(), &filter);
+        assert_eq!(relative(dir.path(), &files.gcda), [PathBuf::from("src/a.gcda")]);
+
+        assert!(Filter::new(&["[".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn doesnt_follow_links() {
+        let outside = tempfile::tempdir().unwrap();
+        touch(&outside.path().join("other.gcno"));
+        touch(&outside.path().join("other.gcda"));
+
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("lib/a.gcno"));
+        touch(&dir.path().join("lib/a.gcda"));
+        // A link to a sibling, one out of the root and one into a loop
+        symlink("lib", dir.path().join("lib2")).unwrap();
+        symlink(outside.path(), dir.path().join("out")).unwrap();
+        symlink("..", dir.path().join("lib/up")).unwrap();
+        symlink("a.gcda", dir.path().join("lib/b.gcda")).unwrap();
+
+        let files = coverage_files(dir.path(), &Filter::default());
+        assert_eq!(relative(dir.path(), &files.gcno), [PathBuf::from("lib/a.gcno")]);
+        assert_eq!(relative(dir.path(), &files.gcda), [PathBuf::from("lib/a.gcda")]);
+
+        // The root itself may be a link
+        let files = coverage_files(&dir.path().join("out"), &Filter::default());
+        assert_eq!(files.gcno, [dir.path().join("out/other.gcno")]);
+    }
+
+    #[test]
+    fn finds_files_that_arent_utf8() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(OsStr::from_bytes(b"\xff/obj\xfe.gcno"));
+        touch(&path);
+
+        assert_eq!(coverage_files(dir.path(), &Filter::default()).gcno, [path]);
+    }
+}